@@ -1,5 +1,12 @@
 ;a_nasm.asm
-global api_putchar
+global api_putchar, api_putstr0, api_putstr1, api_end
+global api_openwin, api_putstrwin, api_boxfilwin, api_point
+global api_refreshwin, api_linewin, api_closewin
+global api_getkey
+global api_alloctimer, api_inittimer, api_settimer, api_freetimer, api_canceltimer
+global api_initmalloc, api_malloc, api_free
+global api_fopen, api_fclose, api_fseek, api_fsize, api_fread
+global api_cmdline
 
 section .text
 
@@ -7,4 +14,255 @@ api_putchar:
 	mov edx,1
 	mov al, [esp+4]
 	int 0x40
-	ret
\ No newline at end of file
+	ret
+
+api_putstr0:	; void api_putstr0(char *s);
+	push ebx
+	mov edx,2
+	mov ebx,[esp+8]	; s
+	int 0x40
+	pop ebx
+	ret
+
+api_putstr1:	; void api_putstr1(char *s, int l);
+	push ebx
+	mov edx,3
+	mov ebx,[esp+8]	; s
+	mov ecx,[esp+12]	; l
+	int 0x40
+	pop ebx
+	ret
+
+api_end:	; void api_end(int status);
+	mov edx,4
+	mov eax,[esp+4]	; status
+	int 0x40
+
+; ウィンドウハンドルの最下位ビットを1にすると描画後のrefreshを省略する
+
+api_openwin:	; int api_openwin(char *buf, int xsiz, int ysiz, int col_inv, char *title);
+	push edi
+	push esi
+	push ebx
+	mov edx,5
+	mov ebx,[esp+16]	; buf
+	mov esi,[esp+20]	; xsiz
+	mov edi,[esp+24]	; ysiz
+	mov eax,[esp+28]	; col_inv
+	mov ecx,[esp+32]	; title
+	int 0x40
+	pop ebx
+	pop esi
+	pop edi
+	ret
+
+api_putstrwin:	; void api_putstrwin(int win, int x, int y, int col, int len, char *str);
+	push edi
+	push esi
+	push ebp
+	push ebx
+	mov edx,6
+	mov ebx,[esp+20]	; win
+	mov esi,[esp+24]	; x
+	mov edi,[esp+28]	; y
+	mov eax,[esp+32]	; col
+	mov ecx,[esp+36]	; len
+	mov ebp,[esp+40]	; str
+	int 0x40
+	pop ebx
+	pop ebp
+	pop esi
+	pop edi
+	ret
+
+api_boxfilwin:	; void api_boxfilwin(int win, int x0, int y0, int x1, int y1, int col);
+	push edi
+	push esi
+	push ebp
+	push ebx
+	mov edx,7
+	mov ebx,[esp+20]	; win
+	mov eax,[esp+24]	; x0
+	mov ecx,[esp+28]	; y0
+	mov esi,[esp+32]	; x1
+	mov edi,[esp+36]	; y1
+	mov ebp,[esp+40]	; col
+	int 0x40
+	pop ebx
+	pop ebp
+	pop esi
+	pop edi
+	ret
+
+api_point:	; void api_point(int win, int x, int y, int col);
+	push edi
+	push esi
+	push ebx
+	mov edx,11
+	mov ebx,[esp+16]	; win
+	mov esi,[esp+20]	; x
+	mov edi,[esp+24]	; y
+	mov eax,[esp+28]	; col
+	int 0x40
+	pop ebx
+	pop esi
+	pop edi
+	ret
+
+api_refreshwin:	; void api_refreshwin(int win, int x0, int y0, int x1, int y1);
+	push edi
+	push esi
+	push ebx
+	mov edx,12
+	mov ebx,[esp+16]	; win
+	mov eax,[esp+20]	; x0
+	mov ecx,[esp+24]	; y0
+	mov esi,[esp+28]	; x1
+	mov edi,[esp+32]	; y1
+	int 0x40
+	pop ebx
+	pop esi
+	pop edi
+	ret
+
+api_linewin:	; void api_linewin(int win, int x0, int y0, int x1, int y1, int col);
+	push edi
+	push esi
+	push ebp
+	push ebx
+	mov edx,13
+	mov ebx,[esp+20]	; win
+	mov eax,[esp+24]	; x0
+	mov ecx,[esp+28]	; y0
+	mov esi,[esp+32]	; x1
+	mov edi,[esp+36]	; y1
+	mov ebp,[esp+40]	; col
+	int 0x40
+	pop ebx
+	pop ebp
+	pop esi
+	pop edi
+	ret
+
+api_closewin:	; void api_closewin(int win);
+	push ebx
+	mov edx,14
+	mov ebx,[esp+8]	; win
+	int 0x40
+	pop ebx
+	ret
+
+api_getkey:	; int api_getkey(int mode);
+	mov edx,15
+	mov eax,[esp+4]	; mode
+	int 0x40
+	ret
+
+api_alloctimer:	; int api_alloctimer(void);
+	mov edx,16
+	int 0x40
+	ret
+
+api_inittimer:	; void api_inittimer(int timer, int data);
+	push ebx
+	mov edx,17
+	mov ebx,[esp+8]	; timer
+	mov eax,[esp+12]	; data
+	int 0x40
+	pop ebx
+	ret
+
+api_settimer:	; void api_settimer(int timer, int time);
+	push ebx
+	mov edx,18
+	mov ebx,[esp+8]	; timer
+	mov eax,[esp+12]	; time
+	int 0x40
+	pop ebx
+	ret
+
+api_freetimer:	; void api_freetimer(int timer);
+	push ebx
+	mov edx,19
+	mov ebx,[esp+8]	; timer
+	int 0x40
+	pop ebx
+	ret
+
+api_canceltimer:	; void api_canceltimer(int timer);
+	push ebx
+	mov edx,20
+	mov ebx,[esp+8]	; timer
+	int 0x40
+	pop ebx
+	ret
+
+; MemManはヘッダのmalloc_start(0x20)に置き，その後ろからデータセグメントの終わりまでをヒープにする
+
+api_initmalloc:	; void api_initmalloc(void);
+	mov edx,8	; malloc領域の番地と大きさはOSが知っている
+	int 0x40
+	ret
+
+api_malloc:	; char *api_malloc(int size);
+	mov edx,9
+	mov ecx,[esp+4]	; size
+	int 0x40
+	ret
+
+api_free:	; void api_free(char *addr, int size);
+	mov edx,10
+	mov eax,[esp+4]	; addr
+	mov ecx,[esp+8]	; size
+	int 0x40
+	ret
+
+api_fopen:	; int api_fopen(char *fname);
+	push ebx
+	mov edx,21
+	mov ebx,[esp+8]	; fname
+	int 0x40
+	pop ebx
+	ret
+
+api_fclose:	; void api_fclose(int fhandle);
+	mov edx,22
+	mov eax,[esp+4]	; fhandle
+	int 0x40
+	ret
+
+api_fseek:	; void api_fseek(int fhandle, int offset, int mode);
+	push ebx
+	mov edx,23
+	mov eax,[esp+8]	; fhandle
+	mov ecx,[esp+16]	; mode
+	mov ebx,[esp+12]	; offset
+	int 0x40
+	pop ebx
+	ret
+
+api_fsize:	; int api_fsize(int fhandle, int mode);
+	mov edx,24
+	mov eax,[esp+4]	; fhandle
+	mov ecx,[esp+8]	; mode
+	int 0x40
+	ret
+
+api_fread:	; int api_fread(char *buf, int maxsize, int fhandle);
+	push ebx
+	mov edx,25
+	mov eax,[esp+16]	; fhandle
+	mov ecx,[esp+12]	; maxsize
+	mov ebx,[esp+8]	; buf
+	int 0x40
+	pop ebx
+	ret
+
+api_cmdline:	; int api_cmdline(char *buf, int maxsize);
+	push ebx
+	mov edx,26
+	mov ecx,[esp+12]	; maxsize
+	mov ebx,[esp+8]	; buf
+	int 0x40
+	pop ebx
+	ret
@@ -1,6 +1,5 @@
 ; haribote-os boot asm
 ; TAB=4
-VBEMODE EQU		0x104
 
 BOTPAK	EQU		0x00280000		; bootpackのロード先
 DSKCAC	EQU		0x00100000		; ディスクキャッシュの場所
@@ -33,30 +32,53 @@ VRAM	EQU		0x0ff8			; グラフィックバッファの開始番地
 		CMP		AX,0x0200
 		JB		scrn320
 
+; 使える画面モードをvbemodesの表の順に試す
+; シートはパレット番号で描くので色数は増えない．8ビットのモードがなかったときだけ16/32ビットにする
+
+		MOV		SI,vbemodes
+trymode:
+		MOV		CX,[SI]
+		CMP		CX,0
+		JE		scrn320					; どれも使えなかった
+
 ; 画面モード情報を得る
 
-		MOV		CX,VBEMODE
 		MOV		AX,0x4f01
 		INT		0x10
 		CMP		AX,0x004f
-		JNE		scrn320
+		JNE		nextmode
 
 ; 画面モード情報の確認
 
-		CMP  	BYTE [ES:DI+0x19],8
-		JNE		scrn320
-		CMP		BYTE [ES:DI+0x1b],4
-		JNE  	scrn320
+		MOV		AL,[SI+2]
+		CMP		[ES:DI+0x19],AL			; 1ピクセルのビット数
+		JNE		nextmode
+		MOV		AL,[SI+3]
+		CMP		[ES:DI+0x1b],AL			; メモリモデル(4:パックドピクセル 6:ダイレクトカラー)
+		JNE		nextmode
+		MOV		AL,[SI+4]
+		CMP		AL,0
+		JE		checklfb
+		CMP		[ES:DI+0x21],AL			; 緑のビット数(16ビットカラーの5:6:5を確かめる)
+		JNE		nextmode
+		MOV		AL,[SI+5]
+		CMP		[ES:DI+0x20],AL			; 赤の位置
+		JNE		nextmode
+checklfb:
 		MOV 	AX,[ES:DI+0x00]
 		AND		AX,0x0080
-		JZ		scrn320					; モード属性のbit7が0だったので諦める
+		JZ		nextmode				; モード属性のbit7が0だったので諦める
 
 ; 画面モードの切り替え
 
-		MOV		BX,VBEMODE+0x4000			
+		MOV		BX,CX
+		ADD		BX,0x4000
 		MOV		AX,0x4f02
 		INT		0x10
-		MOV		BYTE [VMODE],8	; 画面モードをメモ する（C言語が参照する）
+		CMP		AX,0x004f
+		JNE		nextmode
+		MOV		AL,[SI+2]
+		MOV		[VMODE],AL		; 画面モードをメモする（vga::SCREEN_FORMATが参照する）
 		MOV		AX,[ES:DI+0x12]
 		MOV		[SCRNX],AX
 		MOV		AX,[ES:DI+0x14]
@@ -65,6 +87,20 @@ VRAM	EQU		0x0ff8			; グラフィックバッファの開始番地
 		MOV		[VRAM],EAX
 		JMP 	keystatus
 
+nextmode:
+		ADD		SI,6
+		JMP		trymode
+
+; 試す画面モード: モード番号, ビット数, メモリモデル, 緑のビット数, 赤の位置(0なら確認しない)
+vbemodes:
+		DW		0x104
+		DB		8, 4, 0, 0				; パレット(今までのモード)
+		DW		0x118
+		DB		32, 6, 8, 16			; 1024x768 トゥルーカラー(パレットの色をそのまま出せる)
+		DW		0x117
+		DB		16, 6, 6, 11			; 1024x768 ハイカラー
+		DW		0
+
 scrn320:
 
 		MOV 	AL,0x13						; VGAグラフィックス、320x200x8bitカラー
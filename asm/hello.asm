@@ -9,7 +9,8 @@ putloop:
             ADD     ECX,1
             JMP     putloop
 fin:
-            RETF
+            MOV     EAX,0           ; 終了ステータス
+            MOV     EDX,4
+            INT     0x40
 msg:
             DB      "hello",0
-            
\ No newline at end of file
@@ -2,6 +2,8 @@
             MOV     EDX,2
             MOV     EBX,msg
             INT     0x40
-            RETF
+            MOV     EAX,0           ; 終了ステータス
+            MOV     EDX,4
+            INT     0x40
 msg:
-            DB      "hello",0
\ No newline at end of file
+            DB      "hello",0
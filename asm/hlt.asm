@@ -1,3 +1,5 @@
+[BITS 32]
+MOV		EDX,1
 MOV		AL,'h'
 INT		0x40
 MOV		AL,'e'
@@ -8,4 +10,6 @@ MOV		AL,'l'
 INT		0x40
 MOV		AL,'o'
 INT		0x40
-RETF
\ No newline at end of file
+MOV		EAX,0
+MOV		EDX,4
+INT		0x40
\ No newline at end of file
@@ -1,10 +1,10 @@
 void api_putchar(int c);
 
-void HariMain(void) {
+int HariMain(void) {
     api_putchar('h');
     api_putchar('e');
     api_putchar('l');
     api_putchar('l');
     api_putchar('o');
-    return;
+    return 0;
 }
\ No newline at end of file
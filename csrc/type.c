@@ -0,0 +1,28 @@
+void api_putchar(int c);
+int api_fopen(char *fname);
+int api_fread(char *buf, int maxsize, int fhandle);
+void api_fclose(int fhandle);
+int api_cmdline(char *buf, int maxsize);
+
+int HariMain(void) {
+    char s[30], *p, *msg, c;
+    int fh;
+    api_cmdline(s, 30);
+    // アプリ名を読み飛ばして，最初の引数をファイル名とする
+    for (p = s; *p > ' '; p++) {
+    }
+    for (; *p == ' '; p++) {
+    }
+    fh = api_fopen(p);
+    if (fh == 0) {
+        for (msg = "File not found.\n"; *msg != 0; msg++) {
+            api_putchar(*msg);
+        }
+        return 1;
+    }
+    while (api_fread(&c, 1, fh) != 0) {
+        api_putchar(c);
+    }
+    api_fclose(fh);
+    return 0;
+}
@@ -0,0 +1,25 @@
+int api_openwin(char *buf, int xsiz, int ysiz, int col_inv, char *title);
+void api_putstrwin(int win, int x, int y, int col, int len, char *str);
+void api_boxfilwin(int win, int x0, int y0, int x1, int y1, int col);
+void api_point(int win, int x, int y, int col);
+void api_refreshwin(int win, int x0, int y0, int x1, int y1);
+void api_linewin(int win, int x0, int y0, int x1, int y1, int col);
+int api_getkey(int mode);
+
+char buf[150 * 100];
+
+int HariMain(void) {
+    int win, i;
+    win = api_openwin(buf, 150, 100, -1, "hello");
+    api_boxfilwin(win + 1, 8, 36, 141, 91, 7);
+    api_putstrwin(win + 1, 28, 28, 0, 12, "hello, world");
+    for (i = 0; i < 8; i++) {
+        api_linewin(win + 1, 8, 36, 141, 36 + i * 7, i);
+        api_point(win + 1, 16 + i * 8, 80, i + 8);
+    }
+    api_refreshwin(win, 8, 28, 142, 92);
+    // Enterが押されるまで待つ
+    while (api_getkey(1) != 0x0a) {
+    }
+    return 0;
+}
@@ -0,0 +1,33 @@
+#![no_std]
+
+use core::cell::UnsafeCell;
+
+use haribote_sdk::window::Window;
+use haribote_sdk::{args, cmdline, entry, getkey, println};
+
+entry!(main);
+
+// ウィンドウのバッファ．OSが描き続けるのでstaticに置く
+struct WinBuf(UnsafeCell<[u8; 150 * 60]>);
+
+// アプリのタスクは1つで，バッファを借りるのはmainの1回だけ
+unsafe impl Sync for WinBuf {}
+
+static BUF: WinBuf = WinBuf(UnsafeCell::new([0; 150 * 60]));
+
+fn main() -> i32 {
+    let mut line = [0u8; 30];
+    let cmdline = cmdline(&mut line);
+    println!("hello, {} args", args(cmdline).count() - 1);
+
+    let win = match Window::open(unsafe { &mut *BUF.0.get() }, 150, 60, None, "rhello") {
+        Some(win) => win,
+        None => return 1,
+    };
+    win.boxfill(8, 36, 141, 51, 7);
+    win.putstr(28, 36, 0, "hello, rust");
+    // Enterが押されるまで待つ
+    while getkey(true) != Some(0x0a) {}
+    win.close();
+    0
+}
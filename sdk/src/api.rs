@@ -0,0 +1,39 @@
+// asm/a_nasm.asmのAPI関数
+// 番号はconsole::bin_apiのedxに対応する
+
+extern "C" {
+    pub fn api_putchar(c: i32); // 1
+    pub fn api_putstr0(s: *const u8); // 2
+    pub fn api_putstr1(s: *const u8, l: i32); // 3
+    pub fn api_end(status: i32) -> !; // 4
+    pub fn api_openwin(buf: *mut u8, xsiz: i32, ysiz: i32, col_inv: i32, title: *const u8) -> i32; // 5
+    pub fn api_putstrwin(win: i32, x: i32, y: i32, col: i32, len: i32, s: *const u8); // 6
+    pub fn api_boxfilwin(win: i32, x0: i32, y0: i32, x1: i32, y1: i32, col: i32); // 7
+    pub fn api_initmalloc(); // 8
+    pub fn api_malloc(size: i32) -> *mut u8; // 9
+    pub fn api_free(addr: *mut u8, size: i32); // 10
+    pub fn api_point(win: i32, x: i32, y: i32, col: i32); // 11
+    pub fn api_refreshwin(win: i32, x0: i32, y0: i32, x1: i32, y1: i32); // 12
+    pub fn api_linewin(win: i32, x0: i32, y0: i32, x1: i32, y1: i32, col: i32); // 13
+    pub fn api_closewin(win: i32); // 14
+    pub fn api_getkey(mode: i32) -> i32; // 15
+    pub fn api_alloctimer() -> i32; // 16
+    pub fn api_inittimer(timer: i32, data: i32); // 17
+    pub fn api_settimer(timer: i32, time: i32); // 18
+    pub fn api_freetimer(timer: i32); // 19
+    pub fn api_canceltimer(timer: i32); // 20
+    pub fn api_fopen(fname: *const u8) -> i32; // 21
+    pub fn api_fclose(fhandle: i32); // 22
+    pub fn api_fseek(fhandle: i32, offset: i32, mode: i32); // 23
+    pub fn api_fsize(fhandle: i32, mode: i32) -> i32; // 24
+    pub fn api_fread(buf: *mut u8, maxsize: i32, fhandle: i32) -> i32; // 25
+    pub fn api_cmdline(buf: *mut u8, maxsize: i32) -> i32; // 26
+}
+
+// OSに渡す0終端文字列を作る
+// 入りきらない分は切り捨てる
+pub fn to_cstr(s: &str, buf: &mut [u8]) {
+    let len = core::cmp::min(s.len(), buf.len() - 1);
+    buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+    buf[len] = 0;
+}
@@ -0,0 +1,29 @@
+// 色はOSのパレット番号か，rgbで作った0xRRGGBBで指定する
+// 0-15は名前のついた16色，16-231は6x6x6の色の立方体(R, G, Bそれぞれ0, 51, ..., 255の6段階)
+
+pub const BLACK: u8 = 0;
+pub const LIGHT_RED: u8 = 1;
+pub const LIGHT_GREEN: u8 = 2;
+pub const LIGHT_YELLOW: u8 = 3;
+pub const LIGHT_BLUE: u8 = 4;
+pub const LIGHT_PURPLE: u8 = 5;
+pub const LIGHT_CYAN: u8 = 6;
+pub const WHITE: u8 = 7;
+pub const LIGHT_GRAY: u8 = 8;
+pub const DARK_RED: u8 = 9;
+pub const DARK_GREEN: u8 = 10;
+pub const DARK_YELLOW: u8 = 11;
+pub const DARK_BLUE: u8 = 12;
+pub const DARK_PURPLE: u8 = 13;
+pub const DARK_CYAN: u8 = 14;
+pub const DARK_GRAY: u8 = 15;
+
+// 色の引数でこのビットが立っていれば，下位24ビットを0xRRGGBBとして扱う
+pub const RGB_FLAG: i32 = 0x01000000;
+
+// 任意の色．OSが描くときに一番近いパレットの色(16色と立方体の232色)に置き換える
+// 画面が16/32ビットのモードでもウィンドウのバッファはパレット番号なので，この232色に丸められる
+// バッファに直接書くときはパレット番号を使う
+pub const fn rgb(r: u8, g: u8, b: u8) -> i32 {
+    RGB_FLAG | (r as i32) << 16 | (g as i32) << 8 | b as i32
+}
@@ -0,0 +1,55 @@
+use crate::api::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(i32),
+    Current(i32),
+    End(i32),
+}
+
+// 読み込み専用で開いたファイル
+// dropすると閉じる
+pub struct File {
+    handle: i32,
+}
+
+impl File {
+    pub fn open(name: &str) -> Option<File> {
+        let mut cname = [0u8; 32];
+        to_cstr(name, &mut cname);
+        let handle = unsafe { api_fopen(cname.as_ptr()) };
+        if handle == 0 {
+            None
+        } else {
+            Some(File { handle })
+        }
+    }
+
+    // 読み込んだバイト数を返す．0ならファイルの終わり
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        unsafe { api_fread(buf.as_mut_ptr(), buf.len() as i32, self.handle) as usize }
+    }
+
+    pub fn seek(&mut self, pos: SeekFrom) {
+        let (offset, mode) = match pos {
+            SeekFrom::Start(offset) => (offset, 0),
+            SeekFrom::Current(offset) => (offset, 1),
+            SeekFrom::End(offset) => (offset, 2),
+        };
+        unsafe { api_fseek(self.handle, offset, mode) }
+    }
+
+    pub fn size(&self) -> usize {
+        unsafe { api_fsize(self.handle, 0) as usize }
+    }
+
+    pub fn position(&self) -> usize {
+        unsafe { api_fsize(self.handle, 1) as usize }
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        unsafe { api_fclose(self.handle) }
+    }
+}
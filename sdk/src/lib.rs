@@ -0,0 +1,115 @@
+#![no_std]
+
+use core::fmt;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::str::from_utf8;
+
+mod api;
+pub mod color;
+pub mod file;
+pub mod memory;
+pub mod timer;
+pub mod window;
+
+use api::*;
+
+// panicしたときの終了ステータス
+pub const PANIC_STATUS: i32 = 101;
+
+// HariMainの代わりにアプリの開始関数を指定する
+// 開始関数の戻り値がそのまま終了ステータスになる
+//
+// haribote_sdk::entry!(main);
+// fn main() -> i32 { 0 }
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[no_mangle]
+        pub extern "C" fn HariMain() -> i32 {
+            let main: fn() -> i32 = $main;
+            main()
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+// コンソールへの出力
+pub struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        puts(s);
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    Console.write_fmt(args).unwrap();
+}
+
+pub fn putchar(c: u8) {
+    unsafe { api_putchar(c as i32) }
+}
+
+pub fn puts(s: &str) {
+    unsafe { api_putstr1(s.as_ptr(), s.len() as i32) }
+}
+
+// 0終端のバイト列を0の手前まで出力する
+// 0が含まれていなければ全体を出力する
+pub fn putstr0(s: &[u8]) {
+    if s.contains(&0) {
+        unsafe { api_putstr0(s.as_ptr()) }
+    } else {
+        unsafe { api_putstr1(s.as_ptr(), s.len() as i32) }
+    }
+}
+
+// アプリを終了する
+pub fn exit(status: i32) -> ! {
+    unsafe { api_end(status) }
+}
+
+// キー入力(またはタイマ)を受け取る
+// waitがfalseで何も来ていなければNone
+pub fn getkey(wait: bool) -> Option<i32> {
+    let i = unsafe { api_getkey(wait as i32) };
+    if i < 0 {
+        None
+    } else {
+        Some(i)
+    }
+}
+
+// アプリ名を含むコマンドライン全体をbufに読み込む
+pub fn cmdline(buf: &mut [u8]) -> &str {
+    if buf.is_empty() {
+        return "";
+    }
+    let len = unsafe { api_cmdline(buf.as_mut_ptr(), buf.len() as i32) } as usize;
+    from_utf8(&buf[..len]).unwrap_or("")
+}
+
+// コマンドラインを空白で区切ったもの
+// 最初の要素はアプリ名
+pub fn args(cmdline: &str) -> impl Iterator<Item = &str> {
+    cmdline.split(' ').filter(|s| !s.is_empty())
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    println!("{}", info);
+    exit(PANIC_STATUS)
+}
@@ -0,0 +1,43 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::null_mut;
+
+use crate::api::*;
+
+// OSのmallocは16バイト単位で確保する
+const MALLOC_ALIGN: usize = 16;
+
+// OSが決めた位置(.hrbはヘッダのmalloc_start，.elfはスタックの後ろ)からデータセグメントの終わりまでをヒープにする
+// malloc, free, Heapを使う前に1回だけ呼ぶ
+pub fn init_heap() {
+    unsafe { api_initmalloc() }
+}
+
+// 確保できなければnull
+pub fn malloc(size: usize) -> *mut u8 {
+    unsafe { api_malloc(size as i32) }
+}
+
+/// sizeはmallocしたときと同じ大きさ
+///
+/// # Safety
+/// addrはmallocが返したもので，まだfreeしていないこと
+pub unsafe fn free(addr: *mut u8, size: usize) {
+    api_free(addr, size as i32)
+}
+
+// allocクレートを使うときは
+// #[global_allocator] static HEAP: haribote_sdk::memory::Heap = haribote_sdk::memory::Heap;
+pub struct Heap;
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.align() > MALLOC_ALIGN {
+            return null_mut();
+        }
+        malloc(layout.size())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        free(ptr, layout.size())
+    }
+}
@@ -0,0 +1,35 @@
+use crate::api::*;
+
+// アプリ用のタイマ
+// タイムアウトするとinitで指定したデータがgetkeyで受け取れる
+pub struct Timer {
+    index: i32,
+}
+
+impl Timer {
+    pub fn alloc() -> Option<Timer> {
+        let index = unsafe { api_alloctimer() };
+        if index < 0 {
+            None
+        } else {
+            Some(Timer { index })
+        }
+    }
+
+    pub fn init(&mut self, data: i32) {
+        unsafe { api_inittimer(self.index, data) }
+    }
+
+    // timeは1/100秒単位
+    pub fn set(&mut self, time: i32) {
+        unsafe { api_settimer(self.index, time) }
+    }
+
+    pub fn cancel(&mut self) {
+        unsafe { api_canceltimer(self.index) }
+    }
+
+    pub fn free(self) {
+        unsafe { api_freetimer(self.index) }
+    }
+}
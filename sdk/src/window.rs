@@ -0,0 +1,69 @@
+use crate::api::*;
+
+// アプリが開いたウィンドウ
+// 閉じなくてもアプリの終了時にOSが閉じる
+pub struct Window {
+    handle: i32,
+}
+
+impl Window {
+    // bufはウィンドウの描画先で，width * heightバイト以上必要
+    // transparentは透明色(Noneなら透明色なし)
+    pub fn open(
+        buf: &'static mut [u8],
+        width: i32,
+        height: i32,
+        transparent: Option<u8>,
+        title: &str,
+    ) -> Option<Window> {
+        let size = width.checked_mul(height)?;
+        if width <= 0 || height <= 0 || buf.len() < size as usize {
+            return None;
+        }
+        let mut ctitle = [0u8; 32];
+        to_cstr(title, &mut ctitle);
+        let col_inv = transparent.map(|c| c as i32).unwrap_or(-1);
+        let handle =
+            unsafe { api_openwin(buf.as_mut_ptr(), width, height, col_inv, ctitle.as_ptr()) };
+        if handle == 0 {
+            None
+        } else {
+            Some(Window { handle })
+        }
+    }
+
+    // falseにすると描画のたびのrefreshを省略する
+    // まとめて描いた後にrefreshを呼ぶ
+    pub fn set_auto_refresh(&mut self, on: bool) {
+        if on {
+            self.handle &= !1;
+        } else {
+            self.handle |= 1;
+        }
+    }
+
+    // 色はcolorのパレット番号(u8)か，color::rgbで作った色(i32)
+    pub fn putstr(&self, x: i32, y: i32, col: impl Into<i32>, s: &str) {
+        unsafe { api_putstrwin(self.handle, x, y, col.into(), s.len() as i32, s.as_ptr()) }
+    }
+
+    pub fn boxfill(&self, x0: i32, y0: i32, x1: i32, y1: i32, col: impl Into<i32>) {
+        unsafe { api_boxfilwin(self.handle, x0, y0, x1, y1, col.into()) }
+    }
+
+    pub fn point(&self, x: i32, y: i32, col: impl Into<i32>) {
+        unsafe { api_point(self.handle, x, y, col.into()) }
+    }
+
+    pub fn line(&self, x0: i32, y0: i32, x1: i32, y1: i32, col: impl Into<i32>) {
+        unsafe { api_linewin(self.handle, x0, y0, x1, y1, col.into()) }
+    }
+
+    pub fn refresh(&self, x0: i32, y0: i32, x1: i32, y1: i32) {
+        unsafe { api_refreshwin(self.handle, x0, y0, x1, y1) }
+    }
+
+    pub fn close(self) {
+        unsafe { api_closewin(self.handle) }
+    }
+}
@@ -115,13 +115,6 @@ pub extern "C" fn farjmp(eip: i32, cs: i32) {
     }
 }
 
-#[naked]
-pub extern "C" fn farcall(eip: i32, cs: i32) {
-    unsafe {
-        asm!("LCALL *($0)" :: "r"(&Jump {eip, cs}));
-    }
-}
-
 #[macro_export]
 macro_rules! handler {
     ($name: ident) => {{
@@ -130,6 +123,8 @@ macro_rules! handler {
             use crate::timer::NEED_SWITCH;
             use crate::multi_task::{TaskManager, TASK_MANAGER_ADDR};
             unsafe {
+                // アプリ実行中に割り込まれた場合もCPUがTSSのss0/esp0に切り替えてくれるので
+                // ここではOS用のスタックが使える
                 asm!("PUSH ES
                       PUSH DS
                       PUSHAD
@@ -155,30 +150,106 @@ macro_rules! handler {
     }}
 }
 
-#[naked]
-pub extern "C" fn interrupt_print_char() {
-    use crate::console::CONSOLE_ADDR;
-    unsafe {
-        asm!("STI" : : : : "intel");
-        asm!("PUSH 1" : : : : "intel");
-        asm!("AND EAX, 0xff" : : : : "intel");
-        asm!("PUSH EAX" : : : : "intel");
-        asm!("PUSH DWORD PTR [$0]" : : "i"(CONSOLE_ADDR) : : "intel");
-        asm!("CALL console_put_char" : : : : "intel");
-        asm!("ADD ESP, 12" : : : : "intel");
-        asm!("IRETD" : : : : "intel");
-    }
+// CPU例外用のハンドラ．$nameにはpushadした値の番地が渡され，
+// 0以外(tss.esp0の番地)を返すとアプリを強制終了する
+// エラーコードを積まない例外はno_error_codeを指定してダミーの0を積む
+#[macro_export]
+macro_rules! exception_handler {
+    ($name: ident, no_error_code) => {{
+        $crate::exception_handler!(@wrapper $name, "PUSH 0")
+    }};
+    ($name: ident) => {{
+        $crate::exception_handler!(@wrapper $name, "")
+    }};
+    (@wrapper $name: ident, $push_dummy: tt) => {{
+        #[naked]
+        pub extern "C" fn wrapper() {
+            unsafe {
+                asm!($push_dummy : : : : "intel", "volatile");
+                asm!("STI
+                      PUSH ES
+                      PUSH DS
+                      PUSHAD
+                      MOV EAX,ESP
+                      PUSH EAX
+                      MOV AX,SS
+                      MOV DS,AX
+                      MOV ES,AX" : : : : "intel", "volatile");
+                asm!("CALL $0" : : "r"($name as extern "C" fn(*const i32) -> usize) : : "intel");
+                asm!("CMP EAX,0
+                      JNE end_app
+                      POP EAX
+                      POPAD
+                      POP DS
+                      POP ES
+                      ADD ESP,4
+                      IRETD" : : : : "intel", "volatile");
+            }
+        }
+        wrapper
+    }}
 }
 
 #[naked]
 pub extern "C" fn interrupt_bin_api() {
     unsafe {
+        // bin_apiが0以外(tss.esp0の番地)を返したらアプリを終了する
         asm!("STI
+              PUSH DS
+              PUSH ES
               PUSHAD
               PUSHAD
+              MOV AX,SS
+              MOV DS,AX
+              MOV ES,AX
               CALL bin_api
-              ADD ESP, 32
+              CMP EAX,0
+              JNE end_app
+              ADD ESP,32
               POPAD
-              IRETD" : : : : "intel");
+              POP ES
+              POP DS
+              IRETD" : : : : "intel", "volatile");
     }
-}
\ No newline at end of file
+}
+
+// アプリを終了してstart_appの呼び出し元(cmd_app)に戻る
+// EAXにはtss.esp0の番地が入っている
+#[naked]
+#[no_mangle]
+pub extern "C" fn end_app() {
+    unsafe {
+        asm!("MOV ESP,[EAX]
+              MOV DWORD PTR [EAX+4],0
+              POPAD
+              RET" : : : : "intel", "volatile");
+    }
+}
+
+// アプリ用のセグメントに切り替え，RETFでリング3のアプリに飛ぶ
+// OS用のESP/SSはtss.esp0/ss0に保存しておき，割り込みやend_appで使う
+#[naked]
+#[no_mangle]
+pub extern "C" fn start_app(eip: i32, cs: i32, esp: i32, ds: i32, tss_esp0: usize) {
+    unsafe {
+        asm!("PUSHAD
+              MOV EAX,[ESP+36]
+              MOV ECX,[ESP+40]
+              MOV EDX,[ESP+44]
+              MOV EBX,[ESP+48]
+              MOV EBP,[ESP+52]
+              MOV [EBP],ESP
+              MOV [EBP+4],SS
+              MOV ES,BX
+              MOV DS,BX
+              MOV FS,BX
+              MOV GS,BX
+              OR ECX,3
+              OR EBX,3
+              PUSH EBX
+              PUSH EDX
+              PUSH ECX
+              PUSH EAX
+              RETF" : : : : "intel", "volatile");
+    }
+}
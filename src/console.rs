@@ -1,32 +1,149 @@
+use core::cmp::{max, min};
 use core::fmt::Write;
 use core::panic::PanicInfo;
 use core::str::from_utf8;
 
-use crate::asm::{cli, out8, sti, farcall};
-use crate::descriptor_table::{SegmentDescriptor, ADR_GDT, AR_CODE32_ER};
+use crate::asm::{cli, out8, start_app, sti};
+use crate::descriptor_table::{SegmentDescriptor, AR_APP, AR_CODE32_ER, AR_DATA32_RW};
 use crate::fifo::Fifo;
 use crate::interrupt::PORT_KEYDAT;
 use crate::keyboard::{wait_kbc_sendready, KEYBOARD_OFFSET, KEYCMD_LED, KEYTABLE0, KEYTABLE1, LOCK_KEYS};
 use crate::memory::{MemMan, MEMMAN_ADDR};
 use crate::mouse::{Mouse, MouseDec, MOUSE_CURSOR_HEIGHT, MOUSE_CURSOR_WIDTH};
-use crate::multi_task::{TaskManager, TASK_MANAGER_ADDR};
-use crate::sheet::{SheetManager, Sheet};
-use crate::timer::TIMER_MANAGER;
+use crate::multi_task::{Task, TaskManager, TASK_MANAGER_ADDR};
+use crate::sheet::{SheetManager, Sheet, SheetFlag};
+use crate::timer::{with_timer_manager, TimerFlag, TimerManager, TIMER_MANAGER};
 use crate::vga::{
-    boxfill, init_palette, init_screen, make_textbox, make_window, make_wtitle, Color,
-    ScreenWriter, SCREEN_HEIGHT, SCREEN_WIDTH,
+    boxfill, draw_line, draw_point, init_palette, init_screen, make_textbox, make_window,
+    make_wtitle, print_char, Color, Rgb, ScreenWriter, SCREEN_HEIGHT, SCREEN_WIDTH,
+};
+use crate::elf::{is_elf, Elf32Header};
+use crate::file::{
+    file_readfat, FileHandle, FileInfo, ADR_DISKIMG, ADR_FILE_OFFSET, MAX_FAT, MAX_FILE_HANDLES,
+    MAX_FILE_INFO,
+};
+use crate::{
+    write_with_bg, CONSOLE_BACKSPACE, CONSOLE_CURSOR_OFF, CONSOLE_CURSOR_ON, CONSOLE_ENTER,
+    CONSOLE_EXIT, SHEET_MANAGER_ADDR, WINDOW_RESIZE,
 };
-use crate::file::{FileInfo, ADR_DISKIMG, ADR_FILE_OFFSET, MAX_FILE_INFO, MAX_FAT, file_readfat};
-use crate::{write_with_bg, SHEET_MANAGER_ADDR, CONSOLE_ENTER, CONSOLE_BACKSPACE};
 
 pub const MIN_CURSOR_X: isize = 16;
 pub const MIN_CURSOR_Y: isize = 28;
-pub const MAX_CURSOR_X: isize = 248;
-pub const MAX_CURSOR_Y: isize = 140;
-pub const CONSOLE_ADDR: usize = 0xfec;
-pub const CS_BASE_ADDR: usize = 0xfe8;
+pub const CONSOLE_WIDTH: usize = 256;
+pub const CONSOLE_HEIGHT: usize = 165;
+
+const APP_CODE_SEL: i32 = 0 * 8 + 4; // LDTの0番
+const APP_DATA_SEL: i32 = 1 * 8 + 4; // LDTの1番
+const LEGACY_STACK_SIZE: u32 = 64 * 1024;
+const ELF_STACK_SIZE: u32 = 64 * 1024;
+const ELF_HEAP_SIZE: u32 = 128 * 1024; // api_initmallocで使う領域(MemManの32KBを含む)
+const APP_MEMMAN_SIZE: u32 = 32 * 1024; // ヒープの先頭にアプリのMemManを置く分
+const HRB_MAX_SEG_SIZE: u32 = 16 * 1024 * 1024; // ヘッダに書けるデータセグメントの大きさの上限
+const HRB_STUB_ADDR: usize = 0x0c; // 終了APIを呼ぶためにヘッダに書き込むコードの位置
+pub const APP_ABORTED: i32 = -1; // 例外や強制終了で終わったアプリの終了ステータス
+// アプリが開けるウィンドウの大きさの下限(枠とタイトルバーを描ける大きさ)
+const MIN_APP_WINDOW_WIDTH: i32 = 16;
+const MIN_APP_WINDOW_HEIGHT: i32 = 24;
+const API_RGB_FLAG: i32 = 0x01000000; // 色の引数でこのビットが立っていれば下位24ビットが0xRRGGBB
+
+// 新しいコンソールで最初に実行するコマンド(startとncst)
+#[derive(Clone, Copy)]
+pub struct ConsoleStart {
+    pub cmdline: [u8; 30],
+    pub close: bool, // コマンドが終わったらコンソールを閉じる(ncst)
+}
+
+// コンソールのウィンドウを作り，そこに入出力するコンソールタスクを起動する
+// 作ったシートのindexを返す(表示の高さと位置は呼び出し側で決める)
+// startを渡すと，コンソールタスクはそのコマンドを打ち込まれたのと同じように実行する
+pub fn open_console(
+    sheet_manager: &mut SheetManager,
+    memman: &mut MemMan,
+    memtotal: u32,
+    start: Option<ConsoleStart>,
+) -> Option<usize> {
+    let sheet_index = sheet_manager.alloc()?;
+    let buf_addr = match memman.alloc_4k((CONSOLE_WIDTH * CONSOLE_HEIGHT) as u32) {
+        Ok(addr) => addr as usize,
+        Err(_) => {
+            sheet_manager.free(sheet_index);
+            return None;
+        }
+    };
+    // コンソールタスクに渡すコマンド．読んだらコンソールタスクが解放する
+    let start_size = core::mem::size_of::<ConsoleStart>() as u32;
+    let start_addr = match start {
+        Some(start) => match memman.alloc_4k(start_size) {
+            Ok(addr) => {
+                unsafe { *(addr as *mut ConsoleStart) = start };
+                addr as usize
+            }
+            Err(_) => {
+                memman
+                    .free_4k(buf_addr as u32, (CONSOLE_WIDTH * CONSOLE_HEIGHT) as u32)
+                    .unwrap();
+                sheet_manager.free(sheet_index);
+                return None;
+            }
+        },
+        None => 0,
+    };
+    sheet_manager.set_buf(
+        sheet_index,
+        buf_addr,
+        CONSOLE_WIDTH as i32,
+        CONSOLE_HEIGHT as i32,
+        None,
+    );
+    make_console_window(buf_addr, CONSOLE_WIDTH as isize, CONSOLE_HEIGHT as isize, false);
+    sheet_manager.sheets_data[sheet_index].resizable = true;
+    let task_manager = unsafe { &mut *(TASK_MANAGER_ADDR as *mut TaskManager) };
+    match task_manager.spawn(
+        memman,
+        console_task as extern "C" fn(usize, u32, usize),
+        (sheet_index, memtotal, start_addr),
+        64 * 1024,
+        2,
+        2,
+    ) {
+        Ok(task_index) => {
+            if start.map_or(false, |start| start.close) {
+                // ncstのコンソールは表示しないので，Tabで選べるウィンドウには加えない
+                sheet_manager.sheets_data[sheet_index].task = Some(task_index);
+            } else {
+                sheet_manager.add_window(sheet_index, Some(task_index));
+            }
+            Some(sheet_index)
+        }
+        Err(_) => {
+            if start_addr != 0 {
+                memman.free_4k(start_addr as u32, start_size).unwrap();
+            }
+            memman
+                .free_4k(buf_addr as u32, (CONSOLE_WIDTH * CONSOLE_HEIGHT) as u32)
+                .unwrap();
+            sheet_manager.free(sheet_index);
+            None
+        }
+    }
+}
+
+// 文字を出せるカーソル位置の右端と下端
+// テキストボックスは(8, 28)から幅width - 16，高さheight - 37
+// 右端で折り返すので，右端のカーソル位置は8の倍数にそろえる
+fn cursor_limits(width: isize, height: isize) -> (isize, isize) {
+    let cols = (width - 16) / 8;
+    let rows = (height - 37) / 16;
+    (8 + cols * 8, MIN_CURSOR_Y + (rows - 1) * 16)
+}
 
-pub extern "C" fn console_task(sheet_index: usize, memtotal: u32) {
+// コンソールのウィンドウの枠と文字を出す部分を描く
+fn make_console_window(buf_addr: usize, width: isize, height: isize, active: bool) {
+    make_window(buf_addr, width, height, "console", active, true);
+    make_textbox(buf_addr, width, 8, 28, width - 16, height - 37, Color::Black);
+}
+
+pub extern "C" fn console_task(sheet_index: usize, memtotal: u32, start_addr: usize) {
     let task_manager = unsafe { &mut *(TASK_MANAGER_ADDR as *mut TaskManager) };
     let task_index = task_manager.now_index();
 
@@ -39,36 +156,60 @@ pub extern "C" fn console_task(sheet_index: usize, memtotal: u32) {
 
     let sheet_manager_addr = unsafe { SHEET_MANAGER_ADDR };
     let sheet_manager = unsafe { &mut *(sheet_manager_addr as *mut SheetManager) };
-    let sheet = sheet_manager.sheets_data[sheet_index];
 
     let mut console = Console::new(sheet_index, sheet_manager_addr);
-    {
-        let ptr = unsafe { &mut *(CONSOLE_ADDR as *mut usize) };
-        *ptr = &console as *const Console as usize;
-    }
+    // アプリの出力先やbin_apiから使う
+    task_manager.tasks_data[task_index].console_addr = &console as *const Console as usize;
 
     let timer_index = TIMER_MANAGER.lock().alloc().unwrap();
     TIMER_MANAGER.lock().init_timer(timer_index, fifo_addr, 1);
     TIMER_MANAGER.lock().set_time(timer_index, 50);
+    console.timer_index = timer_index;
 
     let memman = unsafe { &mut *(MEMMAN_ADDR as *mut MemMan) };
 
     let fat_addr = memman.alloc_4k(4 * 2880).unwrap();
     let fat = unsafe { &mut *(fat_addr as *mut [u32; 2880]) };
     file_readfat(fat, unsafe { *((ADR_DISKIMG + 0x000200) as *const [u8; 2880 * 4]) });
+    task_manager.tasks_data[task_index].fat_addr = fat_addr as usize;
     
     // プロンプト表示
     console.show_prompt();
     console.cursor_x = 16;
 
+    // start/ncstで渡されたコマンドは，キー入力とEnterとしてFIFOに入れておく
+    let mut close_after_cmd = false;
+    if start_addr != 0 {
+        let start = unsafe { *(start_addr as *const ConsoleStart) };
+        memman
+            .free_4k(start_addr as u32, core::mem::size_of::<ConsoleStart>() as u32)
+            .unwrap();
+        for &c in start.cmdline.iter().take_while(|c| **c != 0) {
+            fifo.put(c as u32 + KEYBOARD_OFFSET).unwrap();
+        }
+        fifo.put(CONSOLE_ENTER + KEYBOARD_OFFSET).unwrap();
+        close_after_cmd = start.close;
+    }
+
     loop {
         cli();
-        if fifo.status() == 0 {
+        if fifo.status() == 0 && !console.pending_exit && !console.pending_resize {
             task_manager.sleep(task_index);
             sti();
         } else {
-            let i = fifo.get().unwrap();
+            // アプリ実行中に後回しにしたものを先に処理する
+            let i = if console.pending_resize {
+                console.pending_resize = false;
+                WINDOW_RESIZE
+            } else if console.pending_exit {
+                console.pending_exit = false;
+                CONSOLE_EXIT
+            } else {
+                fifo.get().unwrap()
+            };
             sti();
+            // 大きさが変わるとバッファも変わるので毎回取り直す(WINDOW_RESIZEの後も)
+            let sheet = sheet_manager.sheets_data[sheet_index];
             if i <= 1 {
                 if i != 0 {
                     TIMER_MANAGER.lock().init_timer(timer_index, fifo_addr, 0);
@@ -86,11 +227,39 @@ pub extern "C" fn console_task(sheet_index: usize, memtotal: u32) {
                 console.cursor_on = true;
             } else if i == 3 {
                 console.cursor_on = false;
+            } else if i == CONSOLE_EXIT {
+                // ウィンドウの閉じるボタンが押されたので，ウィンドウを閉じてタスクを終了する
+                // スタック上のFIFOとConsole，カーソル用のタイマ，FATはexitで解放される
+                task_manager.tasks_data[task_index].console_addr = 0;
+                sheet_manager.free(sheet_index);
+                memman
+                    .free_4k(sheet.buf_addr as u32, (sheet.width * sheet.height) as u32)
+                    .unwrap();
+                task_manager.exit(memman);
+            } else if i == WINDOW_RESIZE {
+                // ウィンドウの枠がドラッグされたので，新しい大きさのバッファに描き直す
+                if let Some((width, height)) = sheet.resize_to {
+                    sheet_manager.sheets_data[sheet_index].resize_to = None;
+                    if let Ok(buf_addr) = memman.alloc_4k((width * height) as u32) {
+                        let buf_addr = buf_addr as usize;
+                        make_console_window(
+                            buf_addr,
+                            width as isize,
+                            height as isize,
+                            console.cursor_on,
+                        );
+                        let old_addr =
+                            sheet_manager.replace_buf(sheet_index, buf_addr, width, height);
+                        memman
+                            .free_4k(old_addr as u32, (sheet.width * sheet.height) as u32)
+                            .unwrap();
+                        console.resize(width as isize, height as isize);
+                    }
+                }
             } else if KEYBOARD_OFFSET <= i && i <= 511 {
                 let key = (i - KEYBOARD_OFFSET) as u8;
                 if key != 0 {
-                    if key == 0x0e {
-                        console.put_chr(key, true);
+                    if key == CONSOLE_BACKSPACE as u8 {
                         if console.cursor_x > MIN_CURSOR_X {
                             console.put_chr(b' ', false);
                             console.cmdline[console.cursor_x as usize / 8 - 2] = b' ';
@@ -105,12 +274,16 @@ pub extern "C" fn console_task(sheet_index: usize, memtotal: u32) {
                         console.cons_newline();
 
                         console.run_cmd(fat, memtotal);
+                        if close_after_cmd {
+                            // アプリが終わってから立てるので，アプリのgetkeyに取られることはない
+                            console.pending_exit = true;
+                        }
                         // プロンプト表示
                         console.show_prompt();
                         console.cursor_x = 16;
                     } else {
                         // 一般文字
-                        if console.cursor_x < MAX_CURSOR_X {
+                        if console.cursor_x < console.max_input_x() {
                             console.cmdline[console.cursor_x as usize / 8 - 2] = key;
                             console.put_chr(key, true)
                         }
@@ -119,6 +292,7 @@ pub extern "C" fn console_task(sheet_index: usize, memtotal: u32) {
             }
 
             if console.cursor_on {
+                let sheet = sheet_manager.sheets_data[sheet_index];
                 boxfill(
                     sheet.buf_addr,
                     sheet.width as isize,
@@ -142,41 +316,638 @@ pub extern "C" fn console_task(sheet_index: usize, memtotal: u32) {
 
 #[no_mangle]
 pub extern "C" fn bin_api(
-    _edi: i32,
-    _esi: i32,
-    _ebp: i32,
-    _esp: i32,
+    edi: i32,
+    esi: i32,
+    ebp: i32,
+    esp: i32,
     ebx: i32,
     edx: i32,
     ecx: i32,
     eax: i32,
-) {
-    let cs_base = unsafe { *(CS_BASE_ADDR as *const usize) };
-    let console_addr = unsafe { *(CONSOLE_ADDR as *const usize) };
+) -> usize {
+    let task_manager = unsafe { &mut *(TASK_MANAGER_ADDR as *mut TaskManager) };
+    let task_index = task_manager.now_index();
+    let console_addr = task_manager.tasks_data[task_index].console_addr;
     let console = unsafe { &mut *(console_addr as *mut Console) };
+    let sheet_manager = unsafe { &mut *(SHEET_MANAGER_ADDR as *mut SheetManager) };
+    // espは保存用にPUSHADしたレジスタの番地．ここに書き込んだ値がアプリに戻るときに復元される
+    // reg[0..8]: EDI, ESI, EBP, ESP, EBX, EDX, ECX, EAX
+    let reg = unsafe { &mut *(esp as *mut [i32; 8]) };
+    // アプリから渡された番地はすべてデータセグメントに収まっているか確かめてから使う
+    // 収まっていなければアプリを異常終了させる
+    macro_rules! app_ptr {
+        ($e:expr) => {
+            match $e {
+                Some(addr) => addr,
+                None => return app_bad_address(console, &mut task_manager.tasks_data[task_index]),
+            }
+        };
+    }
     if edx == 1 {
         // 1文字出力
         console.put_chr(eax as u8, true);
     } else if edx == 2 {
         // 0がくるまで1文字ずつ出力
-        let mut i = 0;
-        loop {
-            let chr = unsafe { *((ebx as usize + i as usize + cs_base) as *const u8) };
-            if chr == 0 {
-                break;
-            }
-            console.put_chr(chr, true);
-            i += 1;
+        let s = app_ptr!(app_cstr(&task_manager.tasks_data[task_index], ebx));
+        for chr in s {
+            console.put_chr(*chr, true);
         }
     } else if edx == 3 {
         // 指定した文字数出力
-        for i in 0..ecx {
-            let chr = unsafe { *((ebx as usize + i as usize + cs_base) as *const u8) };
+        let addr = app_ptr!(app_buf(&task_manager.tasks_data[task_index], ebx, ecx));
+        for i in 0..ecx as usize {
+            let chr = unsafe { *((addr + i) as *const u8) };
             console.put_chr(chr, true);
         }
+    } else if edx == 4 {
+        // アプリ終了
+        // eax: 終了ステータス
+        let task = &mut task_manager.tasks_data[task_index];
+        task.exit_status = eax;
+        return task.esp0_addr();
+    } else if edx == 5 {
+        // ウィンドウを開く
+        // ebx: バッファ, esi: 幅, edi: 高さ, eax: 透明色(-1で透明色なし), ecx: タイトル
+        reg[7] = 0;
+        if esi < MIN_APP_WINDOW_WIDTH || edi < MIN_APP_WINDOW_HEIGHT {
+            return 0;
+        }
+        let buf_addr = app_ptr!(esi
+            .checked_mul(edi)
+            .and_then(|size| app_buf(&task_manager.tasks_data[task_index], ebx, size)));
+        let title = app_ptr!(app_cstr(&task_manager.tasks_data[task_index], ecx));
+        if let Some(sheet_index) = sheet_manager.alloc() {
+            let title = from_utf8(title).unwrap_or("");
+            let transparent = if eax < 0 {
+                None
+            } else {
+                Some(Color(eax as u8)) // バッファの値とそのまま比べる
+            };
+            sheet_manager.set_buf(sheet_index, buf_addr, esi, edi, transparent);
+            sheet_manager.sheets_data[sheet_index].app = true;
+            sheet_manager.add_window(sheet_index, Some(task_index));
+            make_window(buf_addr, esi as isize, edi as isize, title, false, false);
+            sheet_manager.slide(
+                sheet_index,
+                (*SCREEN_WIDTH as i32 - esi) / 2,
+                (*SCREEN_HEIGHT as i32 - edi) / 2,
+            );
+            // マウスと同じ高さを指定して，マウスのすぐ下に表示する
+            let z = sheet_manager.z_max;
+            sheet_manager.updown(sheet_index, z);
+            reg[7] = win_handle(sheet_index);
+        }
+    } else if edx == 6 {
+        // ウィンドウに文字列を表示
+        // ebx: ウィンドウ, esi: x, edi: y, eax: 色, ecx: 文字数, ebp: 文字列
+        let addr = app_ptr!(app_buf(&task_manager.tasks_data[task_index], ebp, ecx));
+        if let Some((sheet_index, refresh)) = app_window(sheet_manager, ebx, task_index) {
+            let sheet = sheet_manager.sheets_data[sheet_index];
+            if ecx <= 0 {
+                return 0;
+            }
+            // 右下の端(この点は含まない)．値はアプリが決めるので桁あふれも確かめる
+            let (x1, y1) = match (
+                ecx.checked_mul(8).and_then(|w| esi.checked_add(w)),
+                edi.checked_add(16),
+            ) {
+                (Some(x1), Some(y1)) => (x1, y1),
+                _ => return 0,
+            };
+            if !in_window(&sheet, esi, edi, x1 - 1, y1 - 1) {
+                return 0;
+            }
+            for i in 0..ecx {
+                let chr = unsafe { *((addr + i as usize) as *const u8) };
+                print_char(
+                    sheet.buf_addr,
+                    sheet.width as usize,
+                    chr,
+                    api_color(eax),
+                    (esi + i * 8) as isize,
+                    edi as isize,
+                );
+            }
+            if refresh {
+                sheet_manager.refresh(sheet_index, esi, edi, x1, y1);
+            }
+        }
+    } else if edx == 7 {
+        // ウィンドウに四角形を描く
+        // ebx: ウィンドウ, eax: x0, ecx: y0, esi: x1, edi: y1, ebp: 色
+        if let Some((sheet_index, refresh)) = app_window(sheet_manager, ebx, task_index) {
+            let sheet = sheet_manager.sheets_data[sheet_index];
+            if !in_window(&sheet, eax, ecx, esi, edi) {
+                return 0;
+            }
+            boxfill(
+                sheet.buf_addr,
+                sheet.width as isize,
+                api_color(ebp),
+                eax as isize,
+                ecx as isize,
+                esi as isize,
+                edi as isize,
+            );
+            if refresh {
+                sheet_manager.refresh(sheet_index, eax, ecx, esi + 1, edi + 1);
+            }
+        }
+    } else if edx == 8 {
+        // アプリ用のメモリ管理の初期化
+        // MemManはmalloc_startに置き，その後ろからデータセグメントの終わりまでを管理する
+        // 番地はOSが決める(.hrbはヘッダのmalloc_start，ELFはスタックの後ろ)ので，
+        // 古いアプリが渡してくるebx, eax, ecxは使わない
+        // 番地はすべてデータセグメント内のもので，OSのMemManには触らない
+        let task = &task_manager.tasks_data[task_index];
+        let heap_start = task.malloc_start + APP_MEMMAN_SIZE as usize;
+        if task.malloc_start == 0 || heap_start > task.ds_size {
+            return 0;
+        }
+        let heap_size = (task.ds_size - heap_start) as u32;
+        let app_memman = app_ptr!(app_memman(task));
+        app_memman.init();
+        app_memman.free(heap_start as u32, heap_size & 0xfffffff0).ok();
+    } else if edx == 9 {
+        // malloc
+        // ecx: 大きさ(16バイト単位に切り上げる)
+        let task = &task_manager.tasks_data[task_index];
+        if task.malloc_start == 0 {
+            reg[7] = 0;
+            return 0;
+        }
+        let app_memman = app_ptr!(app_memman(task));
+        if !app_memman.is_valid(task.ds_start as u32, task.ds_size as u32) {
+            return app_bad_address(console, &mut task_manager.tasks_data[task_index]);
+        }
+        reg[7] = match api_malloc_size(ecx) {
+            Some(size) => app_memman.alloc(size).unwrap_or(0) as i32,
+            None => 0,
+        };
+    } else if edx == 10 {
+        // free
+        // eax: 解放する番地, ecx: 大きさ
+        let task = &task_manager.tasks_data[task_index];
+        if task.malloc_start == 0 {
+            return 0;
+        }
+        let app_memman = app_ptr!(app_memman(task));
+        let (ds_start, ds_size) = (task.ds_start as u32, task.ds_size as u32);
+        if !app_memman.is_valid(ds_start, ds_size) {
+            return app_bad_address(console, &mut task_manager.tasks_data[task_index]);
+        }
+        // 解放する領域もデータセグメントの中でなければ受け付けない
+        if let Some(size) = api_malloc_size(ecx) {
+            if eax as u32 >= ds_start
+                && (eax as u32).checked_add(size).map_or(false, |end| end <= ds_size)
+            {
+                app_memman.free(eax as u32, size).ok();
+            }
+        }
+    } else if edx == 11 {
+        // ウィンドウに点を打つ
+        // ebx: ウィンドウ, esi: x, edi: y, eax: 色
+        if let Some((sheet_index, refresh)) = app_window(sheet_manager, ebx, task_index) {
+            let sheet = sheet_manager.sheets_data[sheet_index];
+            if !in_window(&sheet, esi, edi, esi, edi) {
+                return 0;
+            }
+            draw_point(
+                sheet.buf_addr,
+                sheet.width as isize,
+                api_color(eax),
+                esi as isize,
+                edi as isize,
+            );
+            if refresh {
+                sheet_manager.refresh(sheet_index, esi, edi, esi + 1, edi + 1);
+            }
+        }
+    } else if edx == 12 {
+        // ウィンドウの一部を再描画
+        // ebx: ウィンドウ, eax: x0, ecx: y0, esi: x1, edi: y1
+        // x1, y1は含まない．ウィンドウからはみ出す部分は切り詰める
+        if let Some((sheet_index, _)) = app_window(sheet_manager, ebx, task_index) {
+            let sheet = sheet_manager.sheets_data[sheet_index];
+            let x0 = min(max(eax, 0), sheet.width);
+            let y0 = min(max(ecx, 0), sheet.height);
+            let x1 = min(max(esi, 0), sheet.width);
+            let y1 = min(max(edi, 0), sheet.height);
+            if x0 < x1 && y0 < y1 {
+                sheet_manager.refresh(sheet_index, x0, y0, x1, y1);
+            }
+        }
+    } else if edx == 13 {
+        // ウィンドウに線を引く
+        // ebx: ウィンドウ, eax: x0, ecx: y0, esi: x1, edi: y1, ebp: 色
+        if let Some((sheet_index, refresh)) = app_window(sheet_manager, ebx, task_index) {
+            let sheet = sheet_manager.sheets_data[sheet_index];
+            if !in_window(&sheet, min(eax, esi), min(ecx, edi), max(eax, esi), max(ecx, edi)) {
+                return 0;
+            }
+            draw_line(
+                sheet.buf_addr,
+                sheet.width as isize,
+                api_color(ebp),
+                eax as isize,
+                ecx as isize,
+                esi as isize,
+                edi as isize,
+            );
+            if refresh {
+                sheet_manager.refresh(
+                    sheet_index,
+                    min(eax, esi),
+                    min(ecx, edi),
+                    max(eax, esi) + 1,
+                    max(ecx, edi) + 1,
+                );
+            }
+        }
+    } else if edx == 14 {
+        // ウィンドウを閉じる
+        // ebx: ウィンドウ
+        if let Some((sheet_index, _)) = app_window(sheet_manager, ebx, task_index) {
+            sheet_manager.free(sheet_index);
+        }
+    } else if edx == 15 {
+        // キー入力
+        // eax: 0ならキー入力がなければ-1を返す，1ならキー入力があるまで寝て待つ
+        // アプリ実行中はコンソールのFIFOに来たデータをここで受け取る
+        let fifo_addr = task_manager.tasks_data[task_index].fifo_addr;
+        let fifo = unsafe { &*(fifo_addr as *const Fifo) };
+        loop {
+            cli();
+            if fifo.status() == 0 {
+                if eax != 0 {
+                    // FIFOが空なので寝て待つ
+                    task_manager.sleep(task_index);
+                    sti();
+                    continue;
+                } else {
+                    sti();
+                    reg[7] = -1;
+                    return 0;
+                }
+            }
+            let i = fifo.get().unwrap();
+            sti();
+            if i <= 1 {
+                // カーソル用タイマ
+                // アプリ実行中はカーソルを出さないので，次も表示用の1を注文しておく
+                with_timer_manager(|tm| {
+                    tm.init_timer(console.timer_index, fifo_addr, 1);
+                    tm.set_time(console.timer_index, 50);
+                });
+            } else if i == CONSOLE_CURSOR_ON {
+                console.cursor_on = true;
+            } else if i == CONSOLE_CURSOR_OFF {
+                console.cursor_on = false;
+            } else if i == CONSOLE_EXIT {
+                // アプリを止めてから閉じるよう，コンソールに戻ってから処理する
+                console.pending_exit = true;
+            } else if i == WINDOW_RESIZE {
+                console.pending_resize = true;
+            } else if KEYBOARD_OFFSET <= i {
+                // キーボードデータ(task_aで文字に変換済み)かアプリのタイマ
+                reg[7] = (i - KEYBOARD_OFFSET) as i32;
+                return 0;
+            }
+        }
+    } else if edx == 16 {
+        // タイマの確保
+        reg[7] = with_timer_manager(|tm| {
+            if let Ok(timer_index) = tm.alloc() {
+                tm.timers_data[timer_index].task = Some(task_index);
+                timer_index as i32
+            } else {
+                -1
+            }
+        });
+    } else if edx == 17 {
+        // タイマの初期化
+        // ebx: タイマ, eax: タイムアウト時にgetkeyで受け取るデータ(0以上)
+        // getkeyは何も来ていないとき-1を返すので，負のデータは受け付けない
+        if eax < 0 {
+            return 0;
+        }
+        let fifo_addr = task_manager.tasks_data[task_index].fifo_addr;
+        with_timer_manager(|tm| {
+            if let Some(timer_index) = app_timer(tm, ebx, task_index) {
+                // キーボードと同じ経路でアプリに届くようにする
+                tm.init_timer(timer_index, fifo_addr, eax as u32 + KEYBOARD_OFFSET);
+            }
+        });
+    } else if edx == 18 {
+        // タイマの時間設定
+        // ebx: タイマ, eax: 時間(1/100秒単位．負なら0として扱う)
+        with_timer_manager(|tm| {
+            if let Some(timer_index) = app_timer(tm, ebx, task_index) {
+                tm.cancel(timer_index);
+                tm.set_time(timer_index, max(eax, 0) as u32);
+            }
+        });
+    } else if edx == 19 {
+        // タイマの解放
+        // ebx: タイマ
+        with_timer_manager(|tm| {
+            if let Some(timer_index) = app_timer(tm, ebx, task_index) {
+                tm.cancel(timer_index);
+                tm.free(timer_index);
+            }
+        });
+    } else if edx == 20 {
+        // タイマの取り消し
+        // ebx: タイマ
+        with_timer_manager(|tm| {
+            if let Some(timer_index) = app_timer(tm, ebx, task_index) {
+                tm.cancel(timer_index);
+            }
+        });
+    } else if edx == 21 {
+        // ファイルを開く
+        // ebx: ファイル名
+        // 戻り値はファイルハンドル(1から始まる番号)．開けなかったら0
+        reg[7] = 0;
+        let filename = app_ptr!(app_cstr(&task_manager.tasks_data[task_index], ebx));
+        let task = &mut task_manager.tasks_data[task_index];
+        let free_handle = task.fhandles.iter().position(|fh| fh.buf_addr == 0);
+        if let (Some(i), Some(finfo)) = (free_handle, search_file(filename)) {
+            let memman = unsafe { &mut *(MEMMAN_ADDR as *mut MemMan) };
+            if let Ok(buf_addr) = memman.alloc_4k(FileHandle::buf_size(finfo.size)) {
+                let fat = unsafe { &*(task.fat_addr as *const [u32; MAX_FAT]) };
+                finfo.file_loadfile(buf_addr as usize, fat, ADR_DISKIMG + 0x003e00);
+                task.fhandles[i] = FileHandle {
+                    buf_addr: buf_addr as usize,
+                    size: finfo.size,
+                    pos: 0,
+                };
+                reg[7] = i as i32 + 1;
+            }
+        }
+    } else if edx == 22 {
+        // ファイルを閉じる
+        // eax: ファイルハンドル
+        if let Some(i) = app_fhandle(&task_manager.tasks_data[task_index], eax) {
+            let memman = unsafe { &mut *(MEMMAN_ADDR as *mut MemMan) };
+            task_manager.tasks_data[task_index].fhandles[i].close(memman);
+        }
+    } else if edx == 23 {
+        // ファイルのシーク
+        // eax: ファイルハンドル, ebx: オフセット
+        // ecx: 0ならファイルの先頭から，1なら現在位置から，2ならファイルの終端から
+        if let Some(i) = app_fhandle(&task_manager.tasks_data[task_index], eax) {
+            let fh = &mut task_manager.tasks_data[task_index].fhandles[i];
+            let origin = match ecx {
+                0 => 0,
+                1 => fh.pos as i32,
+                _ => fh.size as i32,
+            };
+            fh.pos = max(0, min(origin.saturating_add(ebx), fh.size as i32)) as u32;
+        }
+    } else if edx == 24 {
+        // ファイルサイズや現在位置の取得
+        // eax: ファイルハンドル
+        // ecx: 0ならファイルサイズ，1なら現在位置，2なら現在位置からファイルの終端までの距離(負)
+        reg[7] = 0;
+        if let Some(i) = app_fhandle(&task_manager.tasks_data[task_index], eax) {
+            let fh = task_manager.tasks_data[task_index].fhandles[i];
+            reg[7] = match ecx {
+                0 => fh.size as i32,
+                1 => fh.pos as i32,
+                _ => fh.pos as i32 - fh.size as i32,
+            };
+        }
+    } else if edx == 25 {
+        // ファイルの読み込み
+        // eax: ファイルハンドル, ebx: バッファ, ecx: 最大で読み込むバイト数
+        // 戻り値は実際に読み込んだバイト数
+        reg[7] = 0;
+        let addr = app_ptr!(app_buf(&task_manager.tasks_data[task_index], ebx, ecx));
+        if let Some(i) = app_fhandle(&task_manager.tasks_data[task_index], eax) {
+            let fh = &mut task_manager.tasks_data[task_index].fhandles[i];
+            let len = min(ecx as u32, fh.size - fh.pos);
+            for j in 0..len as usize {
+                let d = unsafe { &mut *((addr + j) as *mut u8) };
+                *d = unsafe { *((fh.buf_addr + fh.pos as usize + j) as *const u8) };
+            }
+            fh.pos += len;
+            reg[7] = len as i32;
+        }
+    } else if edx == 26 {
+        // コマンドラインの取得
+        // ebx: バッファ, ecx: 最大で書き込むバイト数
+        // アプリ名を含むコマンドライン全体を0終端で書き込み，戻り値は0を含まない長さ
+        let addr = app_ptr!(app_buf(&task_manager.tasks_data[task_index], ebx, ecx));
+        let line = console.command_line();
+        let len = min(line.len(), max(ecx - 1, 0) as usize);
+        for i in 0..len {
+            let d = unsafe { &mut *((addr + i) as *mut u8) };
+            *d = line[i];
+        }
+        if ecx > 0 {
+            let d = unsafe { &mut *((addr + len) as *mut u8) };
+            *d = 0;
+        }
+        reg[7] = len as i32;
     }
+    0
 }
 
+// アプリから渡されたファイルハンドルを，Task::fhandlesのindexに変換する
+fn app_fhandle(task: &Task, handle: i32) -> Option<usize> {
+    if handle < 1 || handle as usize > MAX_FILE_HANDLES {
+        return None;
+    }
+    let i = handle as usize - 1;
+    if task.fhandles[i].buf_addr == 0 {
+        return None;
+    }
+    Some(i)
+}
+
+// ウィンドウハンドルはSheetのindexを1ビット左にずらしたもの
+// 描画系のAPIでは，最下位ビットを1にするとrefreshを省略する
+fn win_handle(sheet_index: usize) -> i32 {
+    (sheet_index << 1) as i32
+}
+
+// アプリから渡されたウィンドウハンドルを(Sheetのindex, refreshするかどうか)に変換する
+// そのアプリが開いたウィンドウでなければNone
+fn app_window(
+    sheet_manager: &SheetManager,
+    handle: i32,
+    task_index: usize,
+) -> Option<(usize, bool)> {
+    let sheet_index = (handle >> 1) as usize;
+    if handle < 0 || sheet_index >= sheet_manager.sheets_data.len() {
+        return None;
+    }
+    let sheet = sheet_manager.sheets_data[sheet_index];
+    if sheet.flag != SheetFlag::USED || !sheet.app || sheet.task != Some(task_index) {
+        return None;
+    }
+    Some((sheet_index, handle & 1 == 0))
+}
+
+// アプリから渡されたタイマの番号を確認する
+// そのアプリが確保したタイマでなければNone
+fn app_timer(tm: &TimerManager, timer: i32, task_index: usize) -> Option<usize> {
+    if timer < 0 || timer as usize >= tm.timers_data.len() {
+        return None;
+    }
+    let t = tm.timers_data[timer as usize];
+    if t.flags == TimerFlag::AVAILABLE || t.task != Some(task_index) {
+        return None;
+    }
+    Some(timer as usize)
+}
+
+// アプリから渡された番地から大きさsizeの領域が，データセグメントに収まっているか確かめる
+// 収まっていればOSから見た番地を返す
+fn app_buf(task: &Task, addr: i32, size: i32) -> Option<usize> {
+    if addr < 0
+        || size < 0
+        || (addr as usize) < task.ds_start
+        || addr as usize + size as usize > task.ds_size
+    {
+        return None;
+    }
+    Some(task.ds_base.wrapping_add(addr as usize))
+}
+
+// アプリのデータセグメント上の0終端文字列
+// データセグメントの終わりまでに0がなければNone
+fn app_cstr<'a>(task: &Task, addr: i32) -> Option<&'a [u8]> {
+    if addr < 0 || (addr as usize) < task.ds_start || addr as usize >= task.ds_size {
+        return None;
+    }
+    let start = task.ds_base.wrapping_add(addr as usize);
+    let max_len = task.ds_size - addr as usize;
+    (0..max_len)
+        .position(|i| unsafe { *((start + i) as *const u8) } == 0)
+        .map(|len| unsafe { core::slice::from_raw_parts(start as *const u8, len) })
+}
+
+// アプリのデータセグメントのmalloc_startに置いたMemMan
+// 中身はアプリが書き換えられるので，使う前にis_validで確かめる
+fn app_memman<'a>(task: &Task) -> Option<&'a mut MemMan> {
+    let addr = app_buf(
+        task,
+        task.malloc_start as i32,
+        core::mem::size_of::<MemMan>() as i32,
+    )?;
+    Some(unsafe { &mut *(addr as *mut MemMan) })
+}
+
+// 不正な番地を渡してきたアプリを異常終了させる
+fn app_bad_address(console: &mut Console, task: &mut Task) -> usize {
+    if console.cursor_x != 8 {
+        console.cursor_x = 8;
+        console.cons_newline();
+    }
+    write!(console, "Bad address in API call.\n").unwrap();
+    task.exit_status = APP_ABORTED;
+    task.esp0_addr()
+}
+
+// malloc/freeの大きさを16バイト単位に切り上げる．桁あふれするならNone
+fn api_malloc_size(size: i32) -> Option<u32> {
+    (size as u32).checked_add(0x0f).map(|size| size & 0xfffffff0)
+}
+
+// アプリから渡された色．パレット番号か，API_RGB_FLAGつきの0xRRGGBB(一番近いパレットの色にする)
+// ウィンドウのバッファはパレット番号なので，画面が16/32ビットのモードでも232色に丸められる
+fn api_color(col: i32) -> Color {
+    if col & API_RGB_FLAG != 0 {
+        Rgb::from_u32(col as u32 & 0xffffff).to_color()
+    } else {
+        Color::from_index(col as u8)
+    }
+}
+
+// (x0, y0)-(x1, y1)がウィンドウの中に収まっているか
+// 収まっていない描画はウィンドウのバッファの外を書き換えてしまうので行わない
+fn in_window(sheet: &Sheet, x0: i32, y0: i32, x1: i32, y1: i32) -> bool {
+    0 <= x0 && x0 <= x1 && x1 < sheet.width && 0 <= y0 && y0 <= y1 && y1 < sheet.height
+}
+
+// CPU例外の処理
+// espにはpushadした値の番地が入っている
+// esp[0..8]: EDI, ESI, EBP, ESP, EBX, EDX, ECX, EAX (PUSHAD)
+// esp[8]: DS, esp[9]: ES, esp[10]: エラーコード, esp[11]: EIP, esp[12]: CS, esp[13]: EFLAGS
+// esp[14]: アプリのESP, esp[15]: アプリのSS (アプリ実行中のみ)
+fn app_exception(esp: *const i32, num: u32, name: &str) -> usize {
+    let regs = unsafe { &*(esp as *const [i32; 16]) };
+    if regs[12] & 3 != 3 {
+        // OS自身が例外を起こした
+        panic!("INT {:02X} : {} EIP = {:08X}", num, name, regs[11]);
+    }
+    let task_manager = unsafe { &mut *(TASK_MANAGER_ADDR as *mut TaskManager) };
+    let task_index = task_manager.now_index();
+    let console_addr = task_manager.tasks_data[task_index].console_addr;
+    let console = unsafe { &mut *(console_addr as *mut Console) };
+    if console.cursor_x != 8 {
+        console.cursor_x = 8;
+        console.cons_newline();
+    }
+    write!(console, "INT {:02X} :\n {}\n", num, name).unwrap();
+    write!(console, "EIP={:08X} ERR={:08X}\n", regs[11], regs[10]).unwrap();
+    write!(console, "EAX={:08X} EBX={:08X}\n", regs[7], regs[4]).unwrap();
+    write!(console, "ECX={:08X} EDX={:08X}\n", regs[6], regs[5]).unwrap();
+    write!(console, "ESI={:08X} EDI={:08X}\n", regs[1], regs[0]).unwrap();
+    write!(console, "EBP={:08X} ESP={:08X}\n", regs[2], regs[14]).unwrap();
+    // 異常終了させる
+    let task = &mut task_manager.tasks_data[task_index];
+    task.exit_status = APP_ABORTED;
+    task.esp0_addr()
+}
+
+pub extern "C" fn inthandler00(esp: *const i32) -> usize {
+    app_exception(esp, 0x00, "Divide Error Exception.")
+}
+
+pub extern "C" fn inthandler06(esp: *const i32) -> usize {
+    app_exception(esp, 0x06, "Invalid Opcode Exception.")
+}
+
+pub extern "C" fn inthandler0c(esp: *const i32) -> usize {
+    app_exception(esp, 0x0c, "Stack Exception.")
+}
+
+pub extern "C" fn inthandler0d(esp: *const i32) -> usize {
+    app_exception(esp, 0x0d, "General Protected Exception.")
+}
+
+pub extern "C" fn inthandler0e(esp: *const i32) -> usize {
+    app_exception(esp, 0x0e, "Page Fault Exception.")
+}
+
+// .hrbファイルの先頭にあるヘッダ(api.ld, hrb.ld参照)
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct HrbHeader {
+    pub seg_size: u32,       // 0x00: データセグメントの大きさ
+    pub signature: [u8; 4],  // 0x04: "Hari"
+    pub mmarea: u32,         // 0x08
+    pub esp: u32,            // 0x0c: スタック初期値&.dataの転送先
+    pub data_size: u32,      // 0x10: .dataの大きさ
+    pub data_hrb: u32,       // 0x14: .dataのファイル上の位置
+    pub jmp: u32,            // 0x18: 0xe9000000
+    pub entry: u32,          // 0x1c: エントリアドレス - 0x20
+    pub malloc_start: u32,   // 0x20: ヒープの開始位置
+}
+
+impl HrbHeader {
+    pub fn is_valid(&self, file_size: u32) -> bool {
+        let data_end = self.data_hrb as u64 + self.data_size as u64;
+        let stack_end = self.esp as u64 + self.data_size as u64;
+        data_end <= file_size as u64
+            && stack_end <= self.seg_size as u64
+            && self.seg_size > 0
+            && self.seg_size <= HRB_MAX_SEG_SIZE
+    }
+}
 
 #[repr(C, packed)]
 pub struct Console {
@@ -187,10 +958,20 @@ pub struct Console {
     pub sheet_index: usize,
     pub sheet_manager_addr: usize,
     pub cmdline: [u8; 30],
+    pub timer_index: usize, // カーソル点滅用のタイマ
+    pub max_cursor_x: isize, // ウィンドウの大きさで決まる文字を出せる範囲
+    pub max_cursor_y: isize,
+    // アプリ実行中に届いたので，アプリが終わってから処理するCONSOLE_EXIT/WINDOW_RESIZE
+    pub pending_exit: bool,
+    pub pending_resize: bool,
 }
 
 impl Console {
     pub fn new(sheet_index: usize, sheet_manager_addr: usize) -> Console {
+        let sheet_manager = unsafe { &*(sheet_manager_addr as *const SheetManager) };
+        let sheet = sheet_manager.sheets_data[sheet_index];
+        let (max_cursor_x, max_cursor_y) =
+            cursor_limits(sheet.width as isize, sheet.height as isize);
         Console {
             cursor_x: MIN_CURSOR_X - 8,
             cursor_y: MIN_CURSOR_Y,
@@ -199,6 +980,32 @@ impl Console {
             sheet_index,
             sheet_manager_addr,
             cmdline: [0; 30],
+            timer_index: 0,
+            max_cursor_x,
+            max_cursor_y,
+            pending_exit: false,
+            pending_resize: false,
+        }
+    }
+
+    // コマンドラインに入力できる右端(cmdlineに入る分まで)
+    pub fn max_input_x(&self) -> isize {
+        min(self.max_cursor_x, (self.cmdline.len() as isize + 1) * 8)
+    }
+
+    // ウィンドウの大きさが変わったので，描き直されたテキストボックスにプロンプトと入力中のコマンドを出し直す
+    pub fn resize(&mut self, width: isize, height: isize) {
+        let (max_cursor_x, max_cursor_y) = cursor_limits(width, height);
+        self.max_cursor_x = max_cursor_x;
+        self.max_cursor_y = max_cursor_y;
+        // 狭くなって入りきらない分は捨てる
+        let input_end = min(self.cursor_x, self.max_input_x());
+        self.cursor_y = MIN_CURSOR_Y;
+        self.show_prompt();
+        self.cursor_x = MIN_CURSOR_X;
+        while self.cursor_x < input_end {
+            let chr = self.cmdline[self.cursor_x as usize / 8 - 2];
+            self.put_chr(chr, true);
         }
     }
 
@@ -234,12 +1041,12 @@ impl Console {
         let sheet_manager = unsafe { &mut *(self.sheet_manager_addr as *mut SheetManager) };
         let sheet = sheet_manager.sheets_data[self.sheet_index];
     
-        if self.cursor_y < MAX_CURSOR_Y {
+        if self.cursor_y < self.max_cursor_y {
             self.cursor_y += 16; // 次の行へ
         } else {
             // スクロール
-            for y in MIN_CURSOR_Y..MAX_CURSOR_Y {
-                for x in 8..(MAX_CURSOR_X + 8) {
+            for y in MIN_CURSOR_Y..self.max_cursor_y {
+                for x in 8..(self.max_cursor_x + 8) {
                     let x = x as usize;
                     let y = y as usize;
                     // スクロール
@@ -251,21 +1058,30 @@ impl Console {
                     }
                 }
             }
-            for y in MAX_CURSOR_Y..(MAX_CURSOR_Y + 16) {
-                for x in 8..(MAX_CURSOR_X + 8) {
+            for y in self.max_cursor_y..(self.max_cursor_y + 16) {
+                for x in 8..(self.max_cursor_x + 8) {
                     let x = x as usize;
                     let y = y as usize;
                     // スクロール
                     let ptr = unsafe {
                         &mut *((sheet.buf_addr + x + y * sheet.width as usize) as *mut u8)
                     };
-                    *ptr = Color::Black as u8;
+                    *ptr = Color::Black.0;
                 }
             }
-            sheet_manager.refresh(self.sheet_index, 8, MIN_CURSOR_Y as i32, MAX_CURSOR_X as i32 + 8, MAX_CURSOR_Y as i32 + 16);
+            sheet_manager.refresh(self.sheet_index, 8, MIN_CURSOR_Y as i32, self.max_cursor_x as i32 + 8, self.max_cursor_y as i32 + 16);
         }
     }
 
+    // 入力されたコマンドライン(0の手前まで)から前後の空白を除いたもの
+    pub fn command_line(&self) -> &[u8] {
+        let len = self.cmdline.iter().position(|c| *c == 0).unwrap_or(self.cmdline.len());
+        let line = &self.cmdline[..len];
+        let start = line.iter().position(|c| *c != b' ').unwrap_or(len);
+        let end = line.iter().rposition(|c| *c != b' ').map_or(start, |i| i + 1);
+        &line[start..end]
+    }
+
     pub fn run_cmd(&mut self, fat: &[u32; MAX_FAT], memtotal: u32) {
         let sheet_manager = unsafe { &mut *(self.sheet_manager_addr as *mut SheetManager) };
         let sheet = sheet_manager.sheets_data[self.sheet_index];
@@ -287,6 +1103,9 @@ impl Console {
             "ls" => self.cmd_ls(),
             "cat" => self.cmd_cat(cmdline_strs, fat),
             "hlt" => self.cmd_hlt(fat),
+            "bench" => self.cmd_bench(),
+            "start" => self.cmd_start(memtotal, false),
+            "ncst" => self.cmd_start(memtotal, true),
             _ => self.cmd_app(&cmd, fat),
         }
         
@@ -328,21 +1147,41 @@ impl Console {
         self.cons_newline();
     }
 
+    // 画面全体の重ね合わせを何回か行い，1回あたりにかかったタイマのカウント(1/100秒)を表示する
+    pub fn cmd_bench(&mut self) {
+        const BENCH_COUNT: u32 = 10;
+        let sheet_manager = unsafe { &*(self.sheet_manager_addr as *const SheetManager) };
+        let start = TIMER_MANAGER.lock().count;
+        for _ in 0..BENCH_COUNT {
+            sheet_manager.redraw_screen();
+        }
+        let ticks = TIMER_MANAGER.lock().count - start;
+        write!(
+            self,
+            "{} refreshes: {} ticks\n{}.{:02} ticks/refresh\n\n",
+            BENCH_COUNT,
+            ticks,
+            ticks / BENCH_COUNT,
+            ticks * 100 / BENCH_COUNT % 100
+        )
+        .unwrap();
+    }
+
     pub fn cmd_clear(&mut self) {
         let sheet_manager = unsafe { &mut *(self.sheet_manager_addr as *mut SheetManager) };
         let sheet = sheet_manager.sheets_data[self.sheet_index];
         for y in MIN_CURSOR_Y..(MIN_CURSOR_Y + 16) {
-            for x in (MIN_CURSOR_X - 8)..(8 + MAX_CURSOR_X) {
+            for x in (MIN_CURSOR_X - 8)..(8 + self.max_cursor_x) {
                 let x = x as usize;
                 let y = y as usize;
                 let ptr = unsafe {
                     &mut *((sheet.buf_addr + x + y * sheet.width as usize) as *mut u8)
                 };
-                *ptr = Color::Black as u8;
+                *ptr = Color::Black.0;
             }
         }    
         sheet_manager.refresh(
-            self.sheet_index, 8, 28, 8+240, 28+128
+            self.sheet_index, 8, 28, self.max_cursor_x as i32, self.max_cursor_y as i32 + 16
         );
         self.cursor_y = MIN_CURSOR_Y;
     }
@@ -406,7 +1245,7 @@ impl Console {
                         // タブ
                         loop {
                             self.put_chr(b' ', true);
-                            if self.cursor_x == 8 + MAX_CURSOR_X {
+                            if self.cursor_x == 8 + self.max_cursor_x {
                                 self.cursor_x = 8;
                                 self.cons_newline();
                             }
@@ -424,7 +1263,7 @@ impl Console {
                     } else {
                         // 普通の文字
                         self.put_chr(p, true);
-                        if self.cursor_x == 8 + MAX_CURSOR_X {
+                        if self.cursor_x == 8 + self.max_cursor_x {
                             // 右端まで来たので改行
                             self.cursor_x = 8;
                             self.cons_newline();
@@ -439,67 +1278,221 @@ impl Console {
         }
     }
 
-    pub fn cmd_hlt(&mut self, fat: &[u32; MAX_FAT]) {
+    // 新しいコンソールを開いて，コマンド名より後ろをそこで実行する
+    // ncst(close)ならコンソールは表示せず，実行が終わったら閉じる
+    pub fn cmd_start(&mut self, memtotal: u32, close: bool) {
+        let sheet_manager = unsafe { &mut *(self.sheet_manager_addr as *mut SheetManager) };
         let memman = unsafe { &mut *(MEMMAN_ADDR as *mut MemMan) };
-        let mut target_finfo = search_file(b"hlt.bin");
-        if let Some(finfo) = target_finfo {
-            let content_addr = memman.alloc_4k(finfo.size).unwrap() as usize;
-            finfo.file_loadfile(content_addr, fat, ADR_DISKIMG + 0x003e00);
-            let gdt = unsafe { &mut *((ADR_GDT + 1003 * 8) as *mut SegmentDescriptor) };    // 1,2,3はdescriptor_table.rsで，1002まではmt.rsで使用済み
-            *gdt = SegmentDescriptor::new(finfo.size - 1, content_addr as i32, AR_CODE32_ER);
-            farcall(0, 1003 * 8);
-            memman.free_4k(content_addr as u32, finfo.size).unwrap();
-            self.cons_newline();
+        let len = self.cmdline.iter().position(|c| *c == 0).unwrap_or(self.cmdline.len());
+        let line = &self.cmdline[..len];
+        // 先頭の空白，コマンド名，その後ろの空白を飛ばす
+        let skip = |from: usize, space: bool| {
+            from + line[from..].iter().position(|c| (*c == b' ') != space).unwrap_or(len - from)
+        };
+        let arg = skip(skip(skip(0, true), false), true);
+        let start = if arg < len {
+            let mut start = ConsoleStart {
+                cmdline: [0; 30],
+                close,
+            };
+            start.cmdline[..len - arg].copy_from_slice(&line[arg..]);
+            Some(start)
+        } else if close {
+            // 何も実行しない見えないコンソールは，閉じる手段がない
+            self.display_error("Bad command.");
+            return;
         } else {
-            self.display_error("File not found");
+            None
+        };
+        match open_console(sheet_manager, memman, memtotal, start) {
+            Some(shi) if !close => {
+                sheet_manager.slide(shi, 32, 4);
+                // マウスのすぐ下に表示する
+                let z = sheet_manager.z_max;
+                sheet_manager.updown(shi, z);
+            }
+            Some(_) => {}
+            None => self.display_error("Cannot open console."),
         }
     }
 
+    pub fn cmd_hlt(&mut self, fat: &[u32; MAX_FAT]) {
+        self.cmd_app(b"hlt.bin", fat);
+    }
+
     pub fn cmd_app<'a>(&mut self, filename: &'a [u8], fat: &[u32; MAX_FAT]) {
         let memman = unsafe { &mut *(MEMMAN_ADDR as *mut MemMan) };
-        let mut finfo = search_file(filename);
-        if let Some(finfo) = finfo {
-            let content_addr = memman.alloc_4k(finfo.size).unwrap() as usize;
-            {
-                let ptr = unsafe { &mut *(CS_BASE_ADDR as *mut usize) };
-                *ptr = content_addr;
-            }
-            finfo.file_loadfile(content_addr, fat, ADR_DISKIMG + 0x003e00);
-            let gdt = unsafe { &mut *((ADR_GDT + 1003 * 8) as *mut SegmentDescriptor) };    // 1,2,3はdescriptor_table.rsで，1002まではmt.rsで使用済み
-            *gdt = SegmentDescriptor::new(finfo.size - 1, content_addr as i32, AR_CODE32_ER);
-            let mut code: [u8; 4] = [0; 4];
-            unsafe {
-                for i in 0..4 {
-                    let s =  &*((content_addr + 4 + i) as *const u8);
-                    code[i] = *s;
-                }
+        let finfo = search_file(filename);
+        if finfo.is_none() {
+            self.display_error("File not found");
+            return;
+        }
+        let finfo = finfo.unwrap();
+        let content_addr = match memman.alloc_4k(finfo.size) {
+            Ok(addr) => addr as usize,
+            Err(_) => {
+                self.display_error("Not enough memory.");
+                return;
+            }
+        };
+        finfo.file_loadfile(content_addr, fat, ADR_DISKIMG + 0x003e00);
+
+        let is_hrb = finfo.size as usize >= core::mem::size_of::<HrbHeader>()
+            && unsafe { *((content_addr + 4) as *const [u8; 4]) } == *b"Hari";
+        let elf_header = if !is_hrb && is_elf(content_addr, finfo.size) {
+            Some(unsafe { *(content_addr as *const Elf32Header) })
+        } else {
+            None
+        };
+        // (データセグメントの使える番地の始まり, データセグメントの大きさ, ESP,
+        //  .dataのファイル上の位置, .dataの大きさ, 開始番地, ヒープ(MemMan)の番地)
+        let (seg_start, seg_size, esp, data_hrb, data_size, entry, malloc_start) = if is_hrb {
+            let header = unsafe { *(content_addr as *const HrbHeader) };
+            if !header.is_valid(finfo.size) {
+                self.display_error(".hrb file format error.");
+                memman.free_4k(content_addr as u32, finfo.size).unwrap();
+                return;
             }
-            let code = from_utf8(&code);
-            if let Ok(code) = code {
-                if finfo.size >= 8 && code == "Hari" {
-                    for i in 0..6 {
-                        let c = unsafe { &mut *((content_addr + i) as *mut u8) };
-                        *c = if i == 0 {
-                            0xe8
-                        } else if i == 1 {
-                            0x16
-                        } else if i == 2 {
-                            0x00
-                        } else if i == 3 {
-                            0x00
-                        } else if i == 4 {
-                            0x00
-                        } else {
-                            0xcb
-                        };
+            // HariMainから戻ってきたら終了APIを呼ぶように，読み終わったヘッダの0x0c-0x17
+            // (esp, data_size, data_hrb)を書き換えてそこから実行する
+            // seg_sizeとmalloc_startはapi_initmallocが参照するので残しておく
+            // CALL 0x1b; MOV EDX,4; INT 0x40
+            // HariMainの戻り値(EAX)がそのまま終了ステータスになる
+            let code: [u8; 12] = [
+                0xe8, 0x0a, 0x00, 0x00, 0x00, 0xba, 0x04, 0x00, 0x00, 0x00, 0xcd, 0x40,
+            ];
+            for i in 0..code.len() {
+                let c = unsafe { &mut *((content_addr + HRB_STUB_ADDR + i) as *mut u8) };
+                *c = code[i];
+            }
+            let entry = HRB_STUB_ADDR as i32;
+            (
+                0,
+                header.seg_size,
+                header.esp,
+                header.data_hrb,
+                header.data_size,
+                entry,
+                header.malloc_start,
+            )
+        } else if let Some(elf_header) = elf_header {
+            // コードもデータも同じセグメントに置き，その後ろをスタックとして使う
+            // スタックの一番上に終了APIを呼ぶコードを置き，e_entryから戻るとそこに来るようにする
+            // さらにその後ろをapi_initmallocで使うヒープにする
+            // 1MBを超えるセグメントは4KB単位になるので，始まりと終わりを4KBにそろえておく
+            // APIには番地をi32で渡すので，セグメントの終わりはi32に収まらなければならない
+            let range = elf_header.image_range(content_addr, finfo.size).and_then(
+                |(image_start, image_end)| {
+                    let stub_addr = ((image_end as u64 + 0x0f) & !0x0f) + ELF_STACK_SIZE as u64;
+                    let seg_size = (stub_addr + 16 + ELF_HEAP_SIZE as u64 + 0xfff) & !0xfff;
+                    if seg_size > core::i32::MAX as u64 {
+                        None
+                    } else {
+                        Some((image_start & !0xfff, seg_size as u32, stub_addr as u32))
                     }
+                },
+            );
+            let (seg_start, seg_size, stub_addr) = match range {
+                Some(range) => range,
+                None => {
+                    self.display_error(".elf file format error.");
+                    memman.free_4k(content_addr as u32, finfo.size).unwrap();
+                    return;
                 }
+            };
+            (
+                seg_start,
+                seg_size,
+                stub_addr - 4,
+                0,
+                0,
+                elf_header.entry as i32,
+                stub_addr + 16,
+            )
+        } else {
+            // ヘッダのない古い形式のアプリは，ファイル全体をデータセグメントの先頭にコピーし
+            // その後ろをスタックとして使う
+            let seg_size = finfo.size + LEGACY_STACK_SIZE;
+            (0, seg_size, seg_size, 0, finfo.size, 0, 0)
+        };
+
+        let task_manager = unsafe { &mut *(TASK_MANAGER_ADDR as *mut TaskManager) };
+        let task_index = task_manager.now_index();
+        // seg_startより下の番地は使わないので確保しない
+        let data_addr = match memman.alloc_4k(seg_size - seg_start) {
+            Ok(addr) => addr as usize,
+            Err(_) => {
+                self.display_error("Not enough memory.");
+                memman.free_4k(content_addr as u32, finfo.size).unwrap();
+                return;
+            }
+        };
+        // セグメント内のseg_start番地がdata_addrに来るようにベースをずらす
+        let seg_base = data_addr.wrapping_sub(seg_start as usize);
+        let esp0_addr = {
+            let task = &mut task_manager.tasks_data[task_index];
+            task.ds_base = seg_base;
+            task.ds_start = seg_start as usize;
+            task.ds_size = seg_size as usize;
+            task.malloc_start = malloc_start as usize;
+            task.exit_status = 0;
+            task.ldt[0] = if elf_header.is_some() {
+                SegmentDescriptor::new(seg_size - 1, seg_base as i32, AR_CODE32_ER + AR_APP)
+            } else {
+                SegmentDescriptor::new(finfo.size - 1, content_addr as i32, AR_CODE32_ER + AR_APP)
+            };
+            task.ldt[1] =
+                SegmentDescriptor::new(seg_size - 1, seg_base as i32, AR_DATA32_RW + AR_APP);
+            task.esp0_addr()
+        };
+        if let Some(elf_header) = elf_header {
+            let (_, image_end) = elf_header.image_range(content_addr, finfo.size).unwrap();
+            elf_header.load(content_addr, seg_base, seg_start, image_end);
+            // MOV EDX,4; INT 0x40
+            // e_entryの戻り値(EAX)がそのまま終了ステータスになる
+            let stub_addr = esp + 4;
+            let code: [u8; 7] = [0xba, 0x04, 0x00, 0x00, 0x00, 0xcd, 0x40];
+            for i in 0..code.len() {
+                let c = unsafe { &mut *(seg_base.wrapping_add(stub_addr as usize + i) as *mut u8) };
+                *c = code[i];
             }
-            farcall(0, 1003 * 8);
-            memman.free_4k(content_addr as u32, finfo.size).unwrap();
-            self.cons_newline();
+            // 戻り先の番地
+            let ret = unsafe { &mut *(seg_base.wrapping_add(esp as usize) as *mut u32) };
+            *ret = stub_addr;
         } else {
-            self.display_error("File not found");
+            // .dataをデータセグメントに転送
+            let dst = if is_hrb { esp } else { 0 } as usize;
+            for i in 0..data_size as usize {
+                let d = unsafe { &mut *((data_addr + dst + i) as *mut u8) };
+                *d = unsafe { *((content_addr + data_hrb as usize + i) as *const u8) };
+            }
+        }
+        start_app(entry, APP_CODE_SEL, esp as i32, APP_DATA_SEL, esp0_addr);
+
+        // ここに戻ってくるのは終了APIか例外，強制終了でend_appを通ったとき
+        // アプリが開いたままのウィンドウやタイマ，ファイルを片付けてから，セグメントを解放する
+        let sheet_manager = unsafe { &mut *(self.sheet_manager_addr as *mut SheetManager) };
+        for i in 0..sheet_manager.sheets_data.len() {
+            let sheet = sheet_manager.sheets_data[i];
+            if sheet.flag == SheetFlag::USED && sheet.app && sheet.task == Some(task_index) {
+                sheet_manager.free(i);
+            }
+        }
+        // タイマも止めて解放しておかないとMAX_TIMER個しかないタイマがなくなってしまう
+        with_timer_manager(|tm| tm.free_task_timers(task_index));
+        for fh in task_manager.tasks_data[task_index].fhandles.iter_mut() {
+            if fh.buf_addr != 0 {
+                fh.close(memman);
+            }
+        }
+        memman.free_4k(data_addr as u32, seg_size - seg_start).unwrap();
+        memman.free_4k(content_addr as u32, finfo.size).unwrap();
+        self.cons_newline();
+
+        let status = task_manager.tasks_data[task_index].exit_status;
+        if status != 0 {
+            self.cursor_x = 8;
+            write!(self, "exit status: {}\n", status).unwrap();
         }
     }
 
@@ -530,6 +1523,12 @@ fn search_file(filename: &[u8]) -> Option<FileInfo> {
     let mut b = [b' '; 8];
     let mut e = [b' '; 3];
     let mut target_finfo: Option<FileInfo> = None;
+    // 8.3形式に収まらない名前のファイルはない
+    if basename.map_or(false, |n| n.len() > b.len())
+        || extname.map_or(false, |n| n.len() > e.len())
+    {
+        return None;
+    }
     if let Some(basename) = basename {
         for x in 0..basename.len() {
             if b'a' <= basename[x] && basename[x] <= b'z' {
@@ -582,8 +1581,21 @@ fn search_file(filename: &[u8]) -> Option<FileInfo> {
     target_finfo
 }
 
-#[no_mangle]
-pub extern "C" fn console_put_char(console: &mut Console, char_num: u8, move_cursor: bool) {
-    console.put_chr(char_num, move_cursor);
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for &c in s.as_bytes() {
+            if c == b'\n' {
+                self.cursor_x = 8;
+                self.cons_newline();
+            } else {
+                self.put_chr(c, true);
+                if self.cursor_x == 8 + self.max_cursor_x {
+                    // 右端まで来たので改行
+                    self.cursor_x = 8;
+                    self.cons_newline();
+                }
+            }
+        }
+        Ok(())
+    }
 }
-
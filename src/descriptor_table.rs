@@ -1,5 +1,7 @@
 use crate::asm;
 use crate::handler;
+use crate::exception_handler;
+use crate::console::{inthandler00, inthandler06, inthandler0c, inthandler0d, inthandler0e};
 use crate::keyboard::inthandler21;
 use crate::mouse::inthandler2c;
 use crate::timer::inthandler20;
@@ -62,9 +64,11 @@ const LIMIT_IDT: i32 = 0x000007ff;
 const ADR_BOTPAK: i32 = 0x00280000;
 const LIMIT_BOTPAK: u32 = 0x0007ffff;
 pub const AR_TSS32: i32 = 0x0089;
+pub const AR_LDT: i32 = 0x0082;
 const AR_INTGATE32: i32 = 0x008e;
-const AR_DATA32_RW: i32 = 0x4092;
+pub const AR_DATA32_RW: i32 = 0x4092;
 pub const AR_CODE32_ER: i32 = 0x409a;
+pub const AR_APP: i32 = 0x60; // DPL=3 (アプリ用)
 
 pub fn init() {
     // GDTの初期化
@@ -85,6 +89,26 @@ pub fn init() {
         *idt = GateDescriptor::new(0, 0, 0);
     }
 
+    // CPU例外の設定
+    let idt = unsafe { &mut *((ADR_IDT + 0x00 * 8) as *mut GateDescriptor) };
+    *idt = GateDescriptor::new(
+        exception_handler!(inthandler00, no_error_code) as u32,
+        2 * 8,
+        AR_INTGATE32,
+    );
+    let idt = unsafe { &mut *((ADR_IDT + 0x06 * 8) as *mut GateDescriptor) };
+    *idt = GateDescriptor::new(
+        exception_handler!(inthandler06, no_error_code) as u32,
+        2 * 8,
+        AR_INTGATE32,
+    );
+    let idt = unsafe { &mut *((ADR_IDT + 0x0c * 8) as *mut GateDescriptor) };
+    *idt = GateDescriptor::new(exception_handler!(inthandler0c) as u32, 2 * 8, AR_INTGATE32);
+    let idt = unsafe { &mut *((ADR_IDT + 0x0d * 8) as *mut GateDescriptor) };
+    *idt = GateDescriptor::new(exception_handler!(inthandler0d) as u32, 2 * 8, AR_INTGATE32);
+    let idt = unsafe { &mut *((ADR_IDT + 0x0e * 8) as *mut GateDescriptor) };
+    *idt = GateDescriptor::new(exception_handler!(inthandler0e) as u32, 2 * 8, AR_INTGATE32);
+
     // 割り込みの設定
     let idt = unsafe { &mut *((ADR_IDT + 0x21 * 8) as *mut GateDescriptor) };
     *idt = GateDescriptor::new(handler!(inthandler21) as u32, 2 * 8, AR_INTGATE32);
@@ -93,7 +117,7 @@ pub fn init() {
     let idt = unsafe { &mut *((ADR_IDT + 0x20 * 8) as *mut GateDescriptor) };
     *idt = GateDescriptor::new(handler!(inthandler20) as u32, 2 * 8, AR_INTGATE32);
     let idt = unsafe { &mut *((ADR_IDT + 0x40 * 8) as *mut GateDescriptor) };
-    *idt = GateDescriptor::new(interrupt_bin_api as u32, 2 * 8, AR_INTGATE32);
+    *idt = GateDescriptor::new(interrupt_bin_api as u32, 2 * 8, AR_INTGATE32 + AR_APP);
 
     load_idtr(LIMIT_IDT, ADR_IDT);
 }
@@ -0,0 +1,124 @@
+// ELF32実行ファイル(i386, ET_EXEC)
+// セグメンテーションだけでアプリを分離しているので，p_vaddrはそのままセグメント内の番地として扱う
+// 普通にリンクした(0x08048000など)ものも読めるように，一番低いPT_LOADのp_vaddrが
+// 確保したメモリの先頭に来るようにセグメントのベースをずらす
+// セグメントは0番地から始まるので，それより下の番地はアプリのものではない(APIでは受け付けない)
+
+pub const ELF_MAX_IMAGE_SIZE: u32 = 4 * 1024 * 1024;
+
+const ET_EXEC: u16 = 2;
+const EM_386: u16 = 3;
+const PT_LOAD: u32 = 1;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct Elf32Header {
+    pub ident: [u8; 16],
+    pub etype: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u32,
+    pub phoff: u32,
+    pub shoff: u32,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct Elf32ProgramHeader {
+    pub ptype: u32,
+    pub offset: u32,
+    pub vaddr: u32,
+    pub paddr: u32,
+    pub filesz: u32,
+    pub memsz: u32,
+    pub flags: u32,
+    pub align: u32,
+}
+
+pub fn is_elf(file_addr: usize, file_size: u32) -> bool {
+    file_size as usize >= core::mem::size_of::<Elf32Header>()
+        && unsafe { *(file_addr as *const [u8; 4]) } == *b"\x7fELF"
+}
+
+impl Elf32Header {
+    pub fn program_header(&self, file_addr: usize, i: usize) -> Elf32ProgramHeader {
+        let addr = file_addr + self.phoff as usize + i * core::mem::size_of::<Elf32ProgramHeader>();
+        unsafe { *(addr as *const Elf32ProgramHeader) }
+    }
+
+    // ヘッダとプログラムヘッダを確かめて，PT_LOADのセグメントを全部置くのに必要な範囲を返す
+    // (一番低いp_vaddr, 一番高いp_vaddr + p_memsz)
+    // 読み込めないファイルならNone
+    pub fn image_range(&self, file_addr: usize, file_size: u32) -> Option<(u32, u32)> {
+        // 32ビット, リトルエンディアン, 実行ファイル, i386
+        if self.ident[4] != 1
+            || self.ident[5] != 1
+            || self.etype != ET_EXEC
+            || self.machine != EM_386
+        {
+            return None;
+        }
+        let phsize = core::mem::size_of::<Elf32ProgramHeader>();
+        if self.phentsize as usize != phsize
+            || self.phoff as u64 + self.phnum as u64 * phsize as u64 > file_size as u64
+        {
+            return None;
+        }
+        let mut image_start: u64 = core::u32::MAX as u64;
+        let mut image_end: u64 = 0;
+        for i in 0..self.phnum as usize {
+            let ph = self.program_header(file_addr, i);
+            if ph.ptype != PT_LOAD {
+                continue;
+            }
+            if ph.filesz > ph.memsz || ph.offset as u64 + ph.filesz as u64 > file_size as u64 {
+                return None;
+            }
+            if ph.memsz == 0 {
+                continue;
+            }
+            let end = ph.vaddr as u64 + ph.memsz as u64;
+            if (ph.vaddr as u64) < image_start {
+                image_start = ph.vaddr as u64;
+            }
+            if end > image_end {
+                image_end = end;
+            }
+        }
+        if image_end == 0
+            || image_end - image_start > ELF_MAX_IMAGE_SIZE as u64
+            || (self.entry as u64) < image_start
+            || self.entry as u64 >= image_end
+        {
+            return None;
+        }
+        Some((image_start as u32, image_end as u32))
+    }
+
+    // PT_LOADのセグメントを，ベースがseg_addrのセグメントに置く
+    // startからendまでは確保してあって，image_rangeが返した範囲を含んでいること
+    // 先にその範囲を0で埋めるので，ファイルにない部分(.bssなど)は0になる
+    pub fn load(&self, file_addr: usize, seg_addr: usize, start: u32, end: u32) {
+        for i in start as usize..end as usize {
+            let d = unsafe { &mut *(seg_addr.wrapping_add(i) as *mut u8) };
+            *d = 0;
+        }
+        for i in 0..self.phnum as usize {
+            let ph = self.program_header(file_addr, i);
+            if ph.ptype != PT_LOAD {
+                continue;
+            }
+            for j in 0..ph.filesz as usize {
+                let d = unsafe { &mut *(seg_addr.wrapping_add(ph.vaddr as usize + j) as *mut u8) };
+                *d = unsafe { *((file_addr + ph.offset as usize + j) as *const u8) };
+            }
+        }
+    }
+}
@@ -1,7 +1,12 @@
+use core::cmp::max;
+
+use crate::memory::MemMan;
+
 pub const ADR_DISKIMG: usize = 0x00100000;
 pub const ADR_FILE_OFFSET: usize = 0x002600;
 pub const MAX_FILE_INFO: usize = 224;
 pub const MAX_FAT: usize = 2880;
+pub const MAX_FILE_HANDLES: usize = 8;
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 #[repr(C, packed)]
@@ -16,6 +21,36 @@ pub struct FileInfo {
     pub size: u32,
 }
 
+// アプリが開いたファイル
+// ファイルの中身はOSのメモリに読み込んでおき，posの位置から読み出す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileHandle {
+    pub buf_addr: usize, // 0なら未使用
+    pub size: u32,
+    pub pos: u32,
+}
+
+impl FileHandle {
+    pub fn new() -> FileHandle {
+        FileHandle {
+            buf_addr: 0,
+            size: 0,
+            pos: 0,
+        }
+    }
+
+    // ファイルを読み込むバッファの大きさ
+    // 大きさ0のファイルでも，開いている印(buf_addr != 0)になる領域を確保する
+    pub fn buf_size(size: u32) -> u32 {
+        max(size, 1)
+    }
+
+    pub fn close(&mut self, memman: &mut MemMan) {
+        memman.free_4k(self.buf_addr as u32, FileHandle::buf_size(self.size)).unwrap();
+        *self = FileHandle::new();
+    }
+}
+
 impl FileInfo {
     pub fn file_loadfile(&self, buf_addr: usize, fat: &[u32; MAX_FAT], img_addr: usize) {
         let mut size = self.size as usize;
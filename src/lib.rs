@@ -3,25 +3,26 @@
 #![feature(start)]
 #![feature(naked_functions)]
 
+use core::cmp::{max, min};
 use core::fmt::Write;
 use core::panic::PanicInfo;
 use core::str::from_utf8;
 
-use asm::{cli, out8, sti};
+use asm::{cli, end_app, out8, sti};
 use fifo::Fifo;
 use interrupt::PORT_KEYDAT;
 use keyboard::{wait_kbc_sendready, KEYBOARD_OFFSET, KEYCMD_LED, KEYTABLE0, KEYTABLE1, LOCK_KEYS};
 use memory::{MemMan, MEMMAN_ADDR};
 use mouse::{Mouse, MouseDec, MOUSE_CURSOR_HEIGHT, MOUSE_CURSOR_WIDTH};
 use multi_task::{TaskManager, TASK_MANAGER_ADDR};
-use sheet::{SheetManager, Sheet};
-use timer::TIMER_MANAGER;
+use sheet::{SheetFlag, SheetManager, Sheet};
+use timer::{with_timer_manager, TIMER_MANAGER};
 use vga::{
-    boxfill, init_palette, init_screen, make_textbox, make_window, make_wtitle, Color,
-    ScreenWriter, SCREEN_HEIGHT, SCREEN_WIDTH,
+    boxfill, change_wtitle, init_palette, init_screen, make_textbox, make_window,
+    wbutton_x, Color, ScreenWriter, WButton, SCREEN_FORMAT, SCREEN_HEIGHT, SCREEN_WIDTH,
 };
 use file::{FileInfo, ADR_DISKIMG, ADR_FILE_OFFSET, MAX_FILE_INFO, file_readfat};
-use console::console_task;
+use console::{open_console, Console, APP_ABORTED};
 
 mod asm;
 mod descriptor_table;
@@ -36,14 +37,25 @@ mod sheet;
 mod timer;
 mod vga;
 mod file;
+mod elf;
 mod console;
 
 static mut SHEET_MANAGER_ADDR: usize = 0;
 const CONSOLE_CURSOR_ON: u32 = 2;
 const CONSOLE_CURSOR_OFF: u32 = 3;
+const CONSOLE_EXIT: u32 = 4;
+const WINDOW_RESIZE: u32 = 5; // シートのresize_toの大きさに描き直してほしい
+
+// 枠をドラッグして変えられるウィンドウの大きさの下限(タイトルとボタン，1行分の文字が入る)
+const MIN_WINDOW_WIDTH: i32 = 136;
+const MIN_WINDOW_HEIGHT: i32 = 69;
 const CONSOLE_ENTER: u32 = 10;
 const CONSOLE_BACKSPACE: u32 = 8;
 
+// task_aのFIFOに入るフレーム更新タイマのデータと，その間隔(1/100秒単位)
+const FRAME_TIMER_DATA: u32 = 2;
+const FRAME_INTERVAL: u32 = 2;
+
 #[no_mangle]
 #[start]
 pub extern "C" fn HariMain() {
@@ -70,6 +82,10 @@ pub extern "C" fn HariMain() {
     let timer_index3 = TIMER_MANAGER.lock().alloc().unwrap();
     TIMER_MANAGER.lock().init_timer(timer_index3, fifo_addr, 1);
     TIMER_MANAGER.lock().set_time(timer_index3, 50);
+    // 画面をバックバッファからVRAMへ転送するタイマ
+    let frame_timer = TIMER_MANAGER.lock().alloc().unwrap();
+    TIMER_MANAGER.lock().init_timer(frame_timer, fifo_addr, FRAME_TIMER_DATA);
+    TIMER_MANAGER.lock().set_time(frame_timer, FRAME_INTERVAL);
 
     let task_manager_addr = memman
         .alloc_4k(core::mem::size_of::<TaskManager>() as u32)
@@ -86,18 +102,21 @@ pub extern "C" fn HariMain() {
         .alloc_4k(core::mem::size_of::<SheetManager>() as u32)
         .unwrap();
     let sheet_manager = unsafe { &mut *(sheet_manager_addr as *mut SheetManager) };
-    let sheet_map_addr = memman
-        .alloc_4k(*SCREEN_HEIGHT as u32 * *SCREEN_WIDTH as u32)
-        .unwrap();
-    *sheet_manager = SheetManager::new(sheet_map_addr as i32);
+    let sheet_map_addr = memman.alloc_4k(SheetManager::map_size()).unwrap();
+    sheet_manager.init(sheet_map_addr as i32);
     let shi_bg = sheet_manager.alloc().unwrap();
     unsafe {
         SHEET_MANAGER_ADDR = sheet_manager_addr as usize;
     }
     let shi_mouse = sheet_manager.alloc().unwrap();
+    sheet_manager.mouse = Some(shi_mouse);
     let shi_win = sheet_manager.alloc().unwrap();
     let scrnx = *SCREEN_WIDTH as i32;
     let scrny = *SCREEN_HEIGHT as i32;
+    // 重ね合わせはバックバッファで行い，フレームごとにまとめてVRAMに転送する
+    let back_size = (scrnx * scrny) as u32 * SCREEN_FORMAT.bytes_per_pixel() as u32;
+    let back_addr = memman.alloc_4k(back_size).unwrap() as usize;
+    sheet_manager.enable_compositor(back_addr);
     let buf_bg_addr = memman.alloc_4k((scrnx * scrny) as u32).unwrap() as usize;
     let buf_win_addr = memman.alloc_4k((144 * 52) as u32).unwrap() as usize;
     let buf_mouse = [0u8; MOUSE_CURSOR_WIDTH * MOUSE_CURSOR_HEIGHT];
@@ -120,57 +139,14 @@ pub extern "C" fn HariMain() {
     let mouse = Mouse::new(buf_mouse_addr);
     mouse.render();
 
-    make_window(buf_win_addr, 144, 52, "task_a", true);
+    make_window(buf_win_addr, 144, 52, "task_a", true, false);
     make_textbox(buf_win_addr, 144, 8, 28, 128, 16, Color::White);
+    // task_aのウィンドウへのキー入力はこのループで直接処理する
+    sheet_manager.add_window(shi_win, None);
 
     task_manager.run(task_a_index, 1, 2);
 
-    const CONSOLE_WIDTH: usize = 256;
-    const CONSOLE_HEIGHT: usize = 165;
-
-    let shi_console = sheet_manager.alloc().unwrap();
-    let buf_console = memman
-        .alloc_4k((CONSOLE_WIDTH * CONSOLE_HEIGHT) as u32)
-        .unwrap() as usize;
-    sheet_manager.set_buf(
-        shi_console,
-        buf_console,
-        CONSOLE_WIDTH as i32,
-        CONSOLE_HEIGHT as i32,
-        None,
-    );
-    make_window(
-        buf_console,
-        CONSOLE_WIDTH as isize,
-        CONSOLE_HEIGHT as isize,
-        "console",
-        false,
-    );
-    make_textbox(
-        buf_console,
-        CONSOLE_WIDTH as isize,
-        8,
-        28,
-        240,
-        128,
-        Color::Black,
-    );
-    let console_task_index = task_manager.alloc().unwrap();
-    let mut console_task_mut = &mut task_manager.tasks_data[console_task_index];
-    let console_esp = memman.alloc_4k(64 * 1024).unwrap() + 64 * 1024 - 12;
-    console_task_mut.tss.esp = console_esp as i32;
-    console_task_mut.tss.eip = console_task as i32;
-    console_task_mut.tss.es = 1 * 8;
-    console_task_mut.tss.cs = 2 * 8;
-    console_task_mut.tss.ss = 1 * 8;
-    console_task_mut.tss.ds = 1 * 8;
-    console_task_mut.tss.fs = 1 * 8;
-    console_task_mut.tss.gs = 1 * 8;
-    let ptr = unsafe { &mut *((console_task_mut.tss.esp + 4) as *mut usize) };
-    *ptr = shi_console;
-    let ptr = unsafe { &mut *((console_task_mut.tss.esp + 8) as *mut u32) };
-    *ptr = memtotal;
-    task_manager.run(console_task_index, 2, 2);
+    let shi_console = open_console(sheet_manager, memman, memtotal, None).unwrap();
 
     sheet_manager.slide(shi_mouse, mx, my);
     sheet_manager.slide(shi_console, 32, 4);
@@ -186,10 +162,15 @@ pub extern "C" fn HariMain() {
     let mut cursor_x = min_cursor_x;
     let mut cursor_c = Color::White;
 
-    let mut active_window: usize = 0;
+    // キー入力を受け付けるウィンドウ(シートのindex)
+    let mut key_win = shi_win;
+    // Shift+F2で開いたコンソールの数(表示位置をずらすため)
+    let mut console_count = 0;
 
     // シフトキー
     let mut key_shift = (false, false);
+    // Altキー
+    let mut key_alt = false;
     // CapsLock, NumLock, ScreenLock
     let mut lock_keys = *LOCK_KEYS;
     let mut keycmd_wait: i32 = -1;
@@ -200,6 +181,16 @@ pub extern "C" fn HariMain() {
 
     let mut cursor_on = true;    // カーソルを点滅するかどうか
 
+    // タイトルバーをつかんで動かしているウィンドウと，前回のマウスの位置
+    let mut drag_win: Option<usize> = None;
+    let mut drag_x = 0;
+    let mut drag_y = 0;
+    let mut left_prev = false; // 前回の左ボタンの状態(押した瞬間だけウィンドウを選ぶ)
+    // 枠をつかんで大きさを変えているウィンドウと，つかんだ辺(右, 下)
+    // つかんだときのマウスの位置はdrag_x, drag_yに入れておく
+    let mut resize_win: Option<usize> = None;
+    let mut resize_edge = (false, false);
+
     loop {
         // キーボードコントローラに送ルデータがあれば送る
         if keycmd.status() > 0 && keycmd_wait < 0 {
@@ -207,6 +198,12 @@ pub extern "C" fn HariMain() {
             wait_kbc_sendready();
             out8(PORT_KEYDAT, keycmd_wait as u8);
         }
+        if sheet_manager.sheets_data[key_win].flag == SheetFlag::AVAILABLE {
+            // キー入力を受け付けていたアプリのウィンドウが閉じられたので，一番上のウィンドウに移す
+            key_win = sheet_manager.top_window().unwrap_or(shi_win);
+            keywin_on(sheet_manager, task_manager, key_win);
+            cursor_on = key_win == shi_win;
+        }
         cli();
         if fifo.status() != 0 {
             let i = fifo.get().unwrap();
@@ -230,7 +227,7 @@ pub extern "C" fn HariMain() {
                     }
                 }
                 if chr != 0 {
-                    if active_window == 0 {
+                    if key_win == shi_win {
                         if cursor_x < max_cursor_x {
                             write_with_bg!(
                                 sheet_manager,
@@ -248,82 +245,92 @@ pub extern "C" fn HariMain() {
                             cursor_x += 8;
                         }
                     } else {
-                        let ctask = task_manager.tasks_data[console_task_index];
-                        let fifo = unsafe { &*(ctask.fifo_addr as *const Fifo) };
-                        fifo.put(chr as u32 + KEYBOARD_OFFSET).unwrap();
+                        send_to_sheet_task(
+                            task_manager,
+                            &sheet_manager.sheets_data[key_win],
+                            chr as u32 + KEYBOARD_OFFSET,
+                        );
                     }
                 }
                 // バックスペース
-                if key == 0x0e && cursor_x > min_cursor_x {
-                    if active_window == 0 {
-                        write_with_bg!(
-                            sheet_manager,
-                            shi_win,
-                            144,
-                            52,
-                            cursor_x,
-                            28,
-                            Color::Black,
-                            Color::White,
-                            1,
-                            " "
-                        );
-                        cursor_x -= 8;
+                if key == 0x0e {
+                    if key_win == shi_win {
+                        if cursor_x > min_cursor_x {
+                            write_with_bg!(
+                                sheet_manager,
+                                shi_win,
+                                144,
+                                52,
+                                cursor_x,
+                                28,
+                                Color::Black,
+                                Color::White,
+                                1,
+                                " "
+                            );
+                            cursor_x -= 8;
+                        }
                     } else {
-                        let ctask = task_manager.tasks_data[console_task_index];
-                        let fifo = unsafe { &*(ctask.fifo_addr as *const Fifo) };
-                        fifo.put(chr as u32 + KEYBOARD_OFFSET).unwrap();
+                        send_to_sheet_task(
+                            task_manager,
+                            &sheet_manager.sheets_data[key_win],
+                            CONSOLE_BACKSPACE + KEYBOARD_OFFSET,
+                        );
                     }
                 }
-                // タブ
-                if key == 0x0f {
-                    let sheet_win = sheet_manager.sheets_data[shi_win];
-                    let sheet_console = sheet_manager.sheets_data[shi_console];
-                    if active_window == 0 {
-                        active_window = 1;
-                        cursor_on = false;  // カーソルを消す
-                        make_wtitle(
-                            sheet_win.buf_addr,
-                            sheet_win.width as isize,
-                            sheet_win.height as isize,
-                            "task_a",
-                            false,
-                        );
-                        make_wtitle(
-                            sheet_console.buf_addr,
-                            sheet_console.width as isize,
-                            sheet_console.height as isize,
-                            "console",
-                            true,
-                        );
+                // Alt+Tab
+                // 開いた順に次のウィンドウを一番上にして，キー入力を移す
+                if key == 0x0f && key_alt {
+                    if let Some(next) = sheet_manager.next_window(key_win) {
+                        if next != key_win {
+                            keywin_off(sheet_manager, task_manager, key_win);
+                            key_win = next;
+                            // マウスのすぐ下に上げる
+                            // 最小化していたウィンドウは表示すると一つ増えるので，マウスと同じ高さを指定する
+                            let z = sheet_manager.z_max.unwrap();
+                            let z = if sheet_manager.sheets_data[key_win].z.is_some() {
+                                z - 1
+                            } else {
+                                z
+                            };
+                            sheet_manager.updown(key_win, Some(z));
+                            keywin_on(sheet_manager, task_manager, key_win);
+                            cursor_on = key_win == shi_win;
+                            if !cursor_on {
+                                // task_aのカーソルを消しておく
+                                cursor_c = Color::White;
+                                boxfill(buf_win_addr, 144, cursor_c, cursor_x, 28, cursor_x + 8, 43);
+                                sheet_manager.refresh(shi_win, cursor_x as i32, 28, cursor_x as i32 + 8, 44);
+                            }
+                        }
+                    }
+                }
+                // Shift+F1 アプリの強制終了
+                // キー入力を受け付けているウィンドウのコンソールで動いているアプリが対象
+                if key == 0x3b && key_shift != (false, false) {
+                    let ctask_index = sheet_manager.sheets_data[key_win].task.unwrap_or(task_a_index);
+                    let ctask = task_manager.tasks_data[ctask_index];
+                    if ctask.tss.ss0 != 0 && ctask.console_addr != 0 {
+                        let console = unsafe { &mut *(ctask.console_addr as *mut Console) };
+                        write!(console, "\nBreak(key) :\n").unwrap();
+                        break_app(task_manager, ctask_index);
+                    }
+                }
+                // Shift+F2 新しいコンソールを開く
+                if key == 0x3c && key_shift != (false, false) {
+                    if let Some(shi) = open_console(sheet_manager, memman, memtotal, None) {
+                        console_count = (console_count + 1) % 8;
+                        sheet_manager.slide(shi, 32 + console_count * 16, 4 + console_count * 16);
+                        // マウスのすぐ下に表示する
+                        let z = sheet_manager.z_max;
+                        sheet_manager.updown(shi, z);
+                        keywin_off(sheet_manager, task_manager, key_win);
+                        key_win = shi;
+                        keywin_on(sheet_manager, task_manager, key_win);
+                        cursor_on = false;
                         cursor_c = Color::White;
-                        let ctask = task_manager.tasks_data[console_task_index];
-                        let fifo = unsafe { &*(ctask.fifo_addr as *const Fifo) };
-                        fifo.put(CONSOLE_CURSOR_ON).unwrap();
-                    } else {
-                        active_window = 0;
-                        cursor_on = true;  // カーソルを表示
-                        make_wtitle(
-                            sheet_win.buf_addr,
-                            sheet_win.width as isize,
-                            sheet_win.height as isize,
-                            "task_a",
-                            true,
-                        );
-                        make_wtitle(
-                            sheet_console.buf_addr,
-                            sheet_console.width as isize,
-                            sheet_console.height as isize,
-                            "console",
-                            false,
-                        );
-                        cursor_c = Color::Black;
-                        let ctask = task_manager.tasks_data[console_task_index];
-                        let fifo = unsafe { &*(ctask.fifo_addr as *const Fifo) };
-                        fifo.put(CONSOLE_CURSOR_OFF).unwrap();
+                        boxfill(buf_win_addr, 144, cursor_c, cursor_x, 28, cursor_x + 8, 43);
                     }
-                    sheet_manager.refresh(shi_win, 0, 0, sheet_win.width, 21);
-                    sheet_manager.refresh(shi_console, 0, 0, sheet_console.width, 21);
                 }
                 // 左シフト ON
                 if key == 0x2a {
@@ -341,6 +348,14 @@ pub extern "C" fn HariMain() {
                 if key == 0xb6 {
                     key_shift.1 = false;
                 }
+                // Alt ON
+                if key == 0x38 {
+                    key_alt = true;
+                }
+                // Alt OFF
+                if key == 0xb8 {
+                    key_alt = false;
+                }
                 // CapsLock
                 if key == 0x3a {
                     lock_keys.caps_lock = !lock_keys.caps_lock;
@@ -361,10 +376,12 @@ pub extern "C" fn HariMain() {
                 }
                 // Enter
                 if key == 0x1c {
-                    if active_window != 0 {
-                        let ctask = task_manager.tasks_data[console_task_index];
-                        let fifo = unsafe { &*(ctask.fifo_addr as *const Fifo) };
-                        fifo.put(CONSOLE_ENTER + KEYBOARD_OFFSET).unwrap();
+                    if key_win != shi_win {
+                        send_to_sheet_task(
+                            task_manager,
+                            &sheet_manager.sheets_data[key_win],
+                            CONSOLE_ENTER + KEYBOARD_OFFSET,
+                        );
                     }
                 }
                 // キーボードがデータを無事に受け取った
@@ -388,11 +405,127 @@ pub extern "C" fn HariMain() {
                         mouse_dec.y.get(),
                     );
                     sheet_manager.slide(shi_mouse, new_x, new_y);
-                    // 左クリックをおしていた場合
-                    if (mouse_dec.btn.get() & 0x01) != 0 {
-                        sheet_manager.slide(shi_win, new_x - 80, new_y - 8);
+                    let left = (mouse_dec.btn.get() & 0x01) != 0;
+                    if !left {
+                        drag_win = None;
+                        if let Some(si) = resize_win.take() {
+                            // ボタンを離したところまで大きさを変える
+                            let sheet = sheet_manager.sheets_data[si];
+                            if sheet.flag == SheetFlag::USED {
+                                let dx = if resize_edge.0 { new_x - drag_x } else { 0 };
+                                let dy = if resize_edge.1 { new_y - drag_y } else { 0 };
+                                let (width, height) = (sheet.width + dx, sheet.height + dy);
+                                if request_resize(sheet_manager, task_manager, si, width, height) {
+                                    sheet_manager.sheets_data[si].restore = None;
+                                }
+                            }
+                        }
+                    } else if let Some(si) = drag_win {
+                        // つかんでいるウィンドウをマウスが動いた分だけ動かす
+                        let sheet = sheet_manager.sheets_data[si];
+                        if sheet.flag == SheetFlag::USED {
+                            sheet_manager.slide(
+                                si,
+                                sheet.x + new_x - drag_x,
+                                sheet.y + new_y - drag_y,
+                            );
+                            drag_x = new_x;
+                            drag_y = new_y;
+                        } else {
+                            drag_win = None;
+                        }
+                    } else if !left_prev {
+                        if let Some(si) = sheet_manager.window_at(new_x, new_y) {
+                            // 左クリックしたウィンドウを一番上にして，キー入力を移す
+                            let z = sheet_manager.z_max.unwrap();
+                            sheet_manager.updown(si, Some(z - 1));
+                            if si != key_win {
+                                keywin_off(sheet_manager, task_manager, key_win);
+                                key_win = si;
+                                keywin_on(sheet_manager, task_manager, key_win);
+                                cursor_on = key_win == shi_win;
+                                if !cursor_on {
+                                    cursor_c = Color::White;
+                                    boxfill(buf_win_addr, 144, cursor_c, cursor_x, 28, cursor_x + 8, 43);
+                                    sheet_manager.refresh(shi_win, cursor_x as i32, 28, cursor_x as i32 + 8, 44);
+                                }
+                            }
+                            let sheet = sheet_manager.sheets_data[si];
+                            let bx = new_x - sheet.x;
+                            let by = new_y - sheet.y;
+                            let on_button = |button| {
+                                match wbutton_x(button, sheet.width as isize, sheet.resizable) {
+                                    Some(x0) => {
+                                        let x0 = x0 as i32;
+                                        x0 <= bx && bx < x0 + 16 && 5 <= by && by < 19
+                                    }
+                                    None => false,
+                                }
+                            };
+                            if on_button(WButton::Close) {
+                                // 閉じるボタン
+                                if let Some(ctask_index) = sheet.task {
+                                    let ctask = task_manager.tasks_data[ctask_index];
+                                    if sheet.app {
+                                        // アプリのウィンドウならアプリを強制終了する(ウィンドウはcmd_appが閉じる)
+                                        // Shift+F1と同じく，アプリが動いているときだけ
+                                        if ctask.tss.ss0 != 0 && ctask.console_addr != 0 {
+                                            let console = unsafe { &mut *(ctask.console_addr as *mut Console) };
+                                            write!(console, "\nBreak(mouse) :\n").unwrap();
+                                            break_app(task_manager, ctask_index);
+                                        }
+                                    } else if ctask.tss.ss0 == 0 {
+                                        // アプリが動いていないコンソールなら閉じる
+                                        send_to_sheet_task(task_manager, &sheet, CONSOLE_EXIT);
+                                    }
+                                }
+                            } else if on_button(WButton::Maximize) {
+                                // 最大化ボタン．最大化しているときは元の位置と大きさに戻す
+                                if let Some((x, y, width, height)) = sheet.restore {
+                                    if request_resize(sheet_manager, task_manager, si, width, height) {
+                                        sheet_manager.sheets_data[si].restore = None;
+                                        sheet_manager.slide(si, x, y);
+                                    }
+                                } else if request_resize(
+                                    sheet_manager,
+                                    task_manager,
+                                    si,
+                                    scrnx,
+                                    scrny - 28, // タスクバーの上まで
+                                ) {
+                                    sheet_manager.sheets_data[si].restore =
+                                        Some((sheet.x, sheet.y, sheet.width, sheet.height));
+                                    sheet_manager.slide(si, 0, 0);
+                                }
+                            } else if on_button(WButton::Minimize) {
+                                // 最小化ボタン．ウィンドウを隠してキー入力を一番上のウィンドウに移す
+                                // 隠したウィンドウはAlt+Tabで選ぶと表示される
+                                keywin_off(sheet_manager, task_manager, key_win);
+                                sheet_manager.updown(si, None);
+                                key_win = sheet_manager.top_window().unwrap_or(shi_win);
+                                keywin_on(sheet_manager, task_manager, key_win);
+                                cursor_on = key_win == shi_win;
+                            } else if sheet.resizable
+                                && (bx >= sheet.width - 4 || by >= sheet.height - 4)
+                            {
+                                // 右か下の枠をつかんだ
+                                resize_win = Some(si);
+                                resize_edge = (bx >= sheet.width - 4, by >= sheet.height - 4);
+                                drag_x = new_x;
+                                drag_y = new_y;
+                            } else if 3 <= bx && bx < sheet.width - 3 && 3 <= by && by < 21 {
+                                // タイトルバーをつかんだ
+                                drag_win = Some(si);
+                                drag_x = new_x;
+                                drag_y = new_y;
+                            }
+                        }
                     }
+                    left_prev = left;
                 }
+            } else if i == FRAME_TIMER_DATA {
+                sheet_manager.flush();
+                with_timer_manager(|tm| tm.set_time(frame_timer, FRAME_INTERVAL));
             } else {
                 if i != 0 {
                     TIMER_MANAGER.lock().init_timer(timer_index3, fifo_addr, 0);
@@ -418,6 +551,77 @@ pub extern "C" fn HariMain() {
     }
 }
 
+// コンソールタスクで動いているアプリを強制終了する
+// コンソールタスクが次に動くときにend_appから再開させる
+fn break_app(task_manager: &mut TaskManager, ctask_index: usize) {
+    cli(); // 強制終了処理中にタスクが変わると困るから
+    {
+        let ctask = &mut task_manager.tasks_data[ctask_index];
+        ctask.tss.eax = ctask.esp0_addr() as i32;
+        ctask.tss.eip = end_app as i32;
+        ctask.exit_status = APP_ABORTED;
+    }
+    sti();
+    // 寝ていたら起こす
+    task_manager.run(ctask_index, -1, 0);
+}
+
+// ウィンドウの大きさを変えるように持ち主のタスクに頼む．頼めたらtrue
+// バッファの確保しなおしと描き直しはタスクが行う(アプリ実行中のコンソールは応じられないので頼まない)
+fn request_resize(
+    sheet_manager: &mut SheetManager,
+    task_manager: &TaskManager,
+    sheet_index: usize,
+    width: i32,
+    height: i32,
+) -> bool {
+    let sheet = sheet_manager.sheets_data[sheet_index];
+    let task_index = match sheet.task {
+        Some(task_index) => task_index,
+        None => return false,
+    };
+    if !sheet.resizable || task_manager.tasks_data[task_index].tss.ss0 != 0 {
+        return false;
+    }
+    let width = min(max(width, MIN_WINDOW_WIDTH), *SCREEN_WIDTH as i32);
+    let height = min(max(height, MIN_WINDOW_HEIGHT), *SCREEN_HEIGHT as i32);
+    if width == sheet.width && height == sheet.height {
+        return false;
+    }
+    sheet_manager.sheets_data[sheet_index].resize_to = Some((width, height));
+    send_to_sheet_task(task_manager, &sheet, WINDOW_RESIZE);
+    true
+}
+
+// シートへのキー入力を受け取るタスクのFIFOにデータを送る
+fn send_to_sheet_task(task_manager: &TaskManager, sheet: &Sheet, data: u32) {
+    if let Some(task_index) = sheet.task {
+        let fifo = unsafe { &*(task_manager.tasks_data[task_index].fifo_addr as *const Fifo) };
+        // FIFOがいっぱいなら捨てる(getkeyしないアプリの実行中など)
+        fifo.put(data).ok();
+    }
+}
+
+// キー入力を受け付けるウィンドウでなくなったときの処理
+// タイトルバーを非アクティブの色にし，コンソールならカーソルを消す
+fn keywin_off(sheet_manager: &SheetManager, task_manager: &TaskManager, sheet_index: usize) {
+    let sheet = sheet_manager.sheets_data[sheet_index];
+    change_wtitle(sheet.buf_addr, sheet.width as isize, false, sheet.resizable);
+    sheet_manager.refresh(sheet_index, 3, 3, sheet.width - 3, 21);
+    if !sheet.app {
+        send_to_sheet_task(task_manager, &sheet, CONSOLE_CURSOR_OFF);
+    }
+}
+
+// キー入力を受け付けるウィンドウになったときの処理
+fn keywin_on(sheet_manager: &SheetManager, task_manager: &TaskManager, sheet_index: usize) {
+    let sheet = sheet_manager.sheets_data[sheet_index];
+    change_wtitle(sheet.buf_addr, sheet.width as isize, true, sheet.resizable);
+    sheet_manager.refresh(sheet_index, 3, 3, sheet.width - 3, 21);
+    if !sheet.app {
+        send_to_sheet_task(task_manager, &sheet, CONSOLE_CURSOR_ON);
+    }
+}
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
@@ -90,6 +90,27 @@ impl MemMan {
         }
     }
 
+    // その場で空の状態に戻す(アプリのデータセグメント上に置いたMemMan用)
+    pub fn init(&mut self) {
+        self.frees = 0;
+        self.maxfrees = 0;
+        self.lostsize = 0;
+        self.losts = 0;
+    }
+
+    // アプリのデータセグメントに置いたMemManはアプリから書き換えられるので，使う前に確かめる
+    // 空き領域はすべてstartからend(アプリが使えるデータセグメントの範囲)の中になければならない
+    pub fn is_valid(&self, start: u32, end: u32) -> bool {
+        // allocはfree[frees]まで読むので，freesはMEMMAN_FREES未満でなければならない
+        if self.frees >= MEMMAN_FREES {
+            return false;
+        }
+        self.free[..self.frees as usize].iter().all(|f| {
+            let (addr, size) = (f.addr, f.size);
+            addr >= start && addr.checked_add(size).map_or(false, |e| e <= end)
+        })
+    }
+
     pub fn total(&self) -> u32 {
         let mut t = 0;
         for i in 0..self.frees {
@@ -103,11 +124,14 @@ impl MemMan {
             let i = i as usize;
             if self.free[i].size >= size {
                 let a = self.free[i].addr;
-                self.free[i].addr += size;
+                self.free[i].addr = a.checked_add(size).ok_or("CANNOT ALLOCATE MEMORY")?;
                 self.free[i].size -= size;
                 if self.free[i].size == 0 {
+                    // 空いた分を前に詰める
                     self.frees -= 1;
-                    self.free[i] = self.free[i + 1]
+                    for j in i..(self.frees as usize) {
+                        self.free[j] = self.free[j + 1];
+                    }
                 }
                 return Ok(a);
             }
@@ -116,36 +140,37 @@ impl MemMan {
     }
 
     pub fn free(&mut self, addr: u32, size: u32) -> Result<(), &'static str> {
-        let mut idx: usize = 0;
+        // アプリのMemManでは番地も大きさもアプリが決めるので，足し算はすべてあふれを確かめる
+        let end = addr.checked_add(size).ok_or("CANNOT FREE MEMORY")?;
+        let frees = self.frees as usize;
         // addrの順に並ぶように、insertすべきindexを決める
-        for i in 0..self.frees {
-            let i = i as usize;
-            if self.free[i].addr > addr {
-                idx = i;
-                break;
-            }
-        }
+        let idx = (0..frees)
+            .find(|&i| self.free[i].addr > addr)
+            .unwrap_or(frees);
         if idx > 0 {
-            if self.free[idx - 1].addr + self.free[idx - 1].size == addr {
-                self.free[idx - 1].size += size;
-                if idx < self.frees as usize {
-                    if addr + size == self.free[idx].addr {
-                        self.free[idx - 1].size += self.free[idx].size;
-                    }
+            let prev = self.free[idx - 1];
+            if prev.addr.checked_add(prev.size) == Some(addr) {
+                // 前とまとめる
+                let mut merged = prev.size.checked_add(size).ok_or("CANNOT FREE MEMORY")?;
+                if idx < frees && end == self.free[idx].addr {
+                    // 後ろともまとめる
+                    let next_size = self.free[idx].size;
+                    merged = merged.checked_add(next_size).ok_or("CANNOT FREE MEMORY")?;
                     self.frees -= 1;
                     for i in idx..(self.frees as usize) {
                         self.free[i] = self.free[i + 1];
                     }
                 }
+                self.free[idx - 1].size = merged;
                 return Ok(());
             }
         }
-        if idx < self.frees as usize {
-            if addr + size == self.free[idx].addr {
-                self.free[idx].addr = addr;
-                self.free[idx].size += size;
-                return Ok(());
-            }
+        if idx < frees && end == self.free[idx].addr {
+            // 後ろとまとめる
+            let next_size = self.free[idx].size;
+            self.free[idx].addr = addr;
+            self.free[idx].size = size.checked_add(next_size).ok_or("CANNOT FREE MEMORY")?;
+            return Ok(());
         }
         if self.frees < MEMMAN_FREES {
             let mut j = self.frees as usize;
@@ -162,7 +187,7 @@ impl MemMan {
             return Ok(());
         }
         self.losts += 1;
-        self.lostsize += size;
+        self.lostsize = self.lostsize.saturating_add(size);
         Err("CANNOT FREE MEMORY")
     }
 
@@ -1,14 +1,16 @@
 use core::default::Default;
 
-use crate::asm::{farjmp, load_tr};
-use crate::descriptor_table::{SegmentDescriptor, ADR_GDT, AR_TSS32};
-use crate::memory::MemMan;
+use crate::asm::{cli, farjmp, hlt, load_eflags, load_tr, store_eflags};
+use crate::descriptor_table::{SegmentDescriptor, ADR_GDT, AR_LDT, AR_TSS32};
+use crate::file::{FileHandle, MAX_FAT, MAX_FILE_HANDLES};
+use crate::memory::{MemMan, MEMMAN_ADDR};
 use crate::timer::TIMER_MANAGER;
 
 const MAX_TASKS: usize = 1000;
 const MAX_TASKS_LV: usize = 100;
 const MAX_TASKLEVELS: usize = 10;
 const TASK_GDT0: i32 = 3;
+const TASK_LDT0: i32 = TASK_GDT0 + MAX_TASKS as i32; // LDTはTSSの後ろに並べる
 
 #[derive(Debug, Default, Clone, Copy)]
 #[repr(C, packed)]
@@ -48,7 +50,18 @@ pub struct Task {
     pub level: usize,
     pub priority: i32,
     pub tss: TSS,
+    pub ldt: [SegmentDescriptor; 2], // アプリ用のコードセグメントとデータセグメント
     pub fifo_addr: usize,
+    pub console_addr: usize, // コンソールタスクならそのConsole(アプリの出力先)
+    pub stack_addr: usize, // alloc_4kで確保したスタック(0ならタスク終了時に解放しない)
+    pub stack_size: u32,
+    pub ds_base: usize,
+    pub ds_start: usize, // アプリが使えるデータセグメント内の番地の始まり(ELFは一番低いp_vaddr)
+    pub ds_size: usize, // アプリのデータセグメントの大きさ
+    pub malloc_start: usize, // アプリのMemManを置く番地(0ならヒープなし)
+    pub exit_status: i32, // アプリの終了ステータス
+    pub fat_addr: usize,
+    pub fhandles: [FileHandle; MAX_FILE_HANDLES], // アプリが開いているファイル
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -66,9 +79,25 @@ impl Task {
             level: 0,
             priority: 2,
             tss: Default::default(),
+            ldt: [SegmentDescriptor::new(0, 0, 0); 2],
             fifo_addr: 0,
+            console_addr: 0,
+            stack_addr: 0,
+            stack_size: 0,
+            ds_base: 0,
+            ds_start: 0,
+            ds_size: 0,
+            malloc_start: 0,
+            exit_status: 0,
+            fat_addr: 0,
+            fhandles: [FileHandle::new(); MAX_FILE_HANDLES],
         }
     }
+
+    // start_appやend_appが使うtss.esp0の番地
+    pub fn esp0_addr(&self) -> usize {
+        &self.tss as *const TSS as usize + 4
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -95,6 +124,84 @@ pub struct TaskManager {
     pub tasks_data: [Task; MAX_TASKS],
 }
 
+// タスクの開始関数に渡せる引数
+// スタックに4バイトずつ積むので，4バイトに収まる型だけ
+pub trait TaskArg: Copy {
+    fn to_stack(self) -> u32;
+}
+
+impl TaskArg for u32 {
+    fn to_stack(self) -> u32 {
+        self
+    }
+}
+
+impl TaskArg for i32 {
+    fn to_stack(self) -> u32 {
+        self as u32
+    }
+}
+
+impl TaskArg for usize {
+    fn to_stack(self) -> u32 {
+        self as u32
+    }
+}
+
+// spawnに渡せるタスクの開始関数(引数は3つまで)
+pub trait TaskEntry: Copy {
+    type Args;
+    fn eip(self) -> i32;
+    // espはリターンアドレスの番地．引数はその後ろ(esp+4から)に並べる
+    fn write_args(args: Self::Args, esp: usize);
+}
+
+fn write_arg<A: TaskArg>(arg: A, addr: usize) {
+    let ptr = unsafe { &mut *(addr as *mut u32) };
+    *ptr = arg.to_stack();
+}
+
+impl TaskEntry for extern "C" fn() {
+    type Args = ();
+    fn eip(self) -> i32 {
+        self as i32
+    }
+    fn write_args(_args: (), _esp: usize) {}
+}
+
+impl<A: TaskArg> TaskEntry for extern "C" fn(A) {
+    type Args = (A,);
+    fn eip(self) -> i32 {
+        self as i32
+    }
+    fn write_args(args: (A,), esp: usize) {
+        write_arg(args.0, esp + 4);
+    }
+}
+
+impl<A: TaskArg, B: TaskArg> TaskEntry for extern "C" fn(A, B) {
+    type Args = (A, B);
+    fn eip(self) -> i32 {
+        self as i32
+    }
+    fn write_args(args: (A, B), esp: usize) {
+        write_arg(args.0, esp + 4);
+        write_arg(args.1, esp + 8);
+    }
+}
+
+impl<A: TaskArg, B: TaskArg, C: TaskArg> TaskEntry for extern "C" fn(A, B, C) {
+    type Args = (A, B, C);
+    fn eip(self) -> i32 {
+        self as i32
+    }
+    fn write_args(args: (A, B, C), esp: usize) {
+        write_arg(args.0, esp + 4);
+        write_arg(args.1, esp + 8);
+        write_arg(args.2, esp + 12);
+    }
+}
+
 pub static mut TASK_MANAGER_ADDR: usize = 0;
 pub static mut MT_TIMER_INDEX: usize = 1001;
 
@@ -167,6 +274,14 @@ impl TaskManager {
             let gdt =
                 unsafe { &mut *((ADR_GDT + (TASK_GDT0 + i as i32) * 8) as *mut SegmentDescriptor) };
             *gdt = SegmentDescriptor::new(103, &(task.tss) as *const TSS as i32, AR_TSS32);
+            task.tss.ldtr = (TASK_LDT0 + i as i32) * 8;
+            let gdt =
+                unsafe { &mut *((ADR_GDT + (TASK_LDT0 + i as i32) * 8) as *mut SegmentDescriptor) };
+            *gdt = SegmentDescriptor::new(
+                15,
+                &(task.ldt) as *const [SegmentDescriptor; 2] as i32,
+                AR_LDT,
+            );
         }
         let task_index = self.alloc()?;
         {
@@ -187,19 +302,14 @@ impl TaskManager {
         unsafe {
             MT_TIMER_INDEX = timer_index_ts;
         }
-        {
-            let idle_index = self.alloc().unwrap();
-            let mut idle = &mut self.tasks_data[idle_index];
-            idle.tss.esp = memman.alloc_4k(64 * 1024).unwrap() as i32 + 64 * 1024;
-            idle.tss.eip = task_idle as i32;
-            idle.tss.es = 1 * 8;
-            idle.tss.cs = 2 * 8;
-            idle.tss.ss = 1 * 8;
-            idle.tss.ds = 1 * 8;
-            idle.tss.fs = 1 * 8;
-            idle.tss.gs = 1 * 8;
-            self.run(idle_index, MAX_TASKLEVELS as i32 - 1, 1);
-        }
+        self.spawn(
+            memman,
+            task_idle as extern "C" fn(),
+            (),
+            64 * 1024,
+            MAX_TASKLEVELS as i32 - 1,
+            1,
+        )?;
 
         Ok(task_index)
     }
@@ -211,14 +321,60 @@ impl TaskManager {
                 task.flag = TaskFlag::USED;
                 task.tss.eflags = 0x00000202; /* IF = 1; */
                 task.tss.iomap = 0x40000000;
+                task.tss.ss0 = 0; // アプリ実行中のみ0以外になる
                 return Ok(i);
             }
         }
         return Err("CANNOT ALLOCATE TASK");
     }
 
+    // スタックを確保し，entry(args)から始まるOSのタスクを作って動かす
+    // entryから戻るとそのタスクは終了する
+    pub fn spawn<F: TaskEntry>(
+        &mut self,
+        memman: &mut MemMan,
+        entry: F,
+        args: F::Args,
+        stack_size: u32,
+        level: i32,
+        priority: i32,
+    ) -> Result<usize, &'static str> {
+        let task_index = self.alloc()?;
+        let stack_addr = match memman.alloc_4k(stack_size) {
+            Ok(addr) => addr as usize,
+            Err(e) => {
+                self.tasks_data[task_index].flag = TaskFlag::AVAILABLE;
+                return Err(e);
+            }
+        };
+        // リターンアドレスと引数3つ分を空けておく
+        let esp = stack_addr + stack_size as usize - 16;
+        {
+            let mut task = &mut self.tasks_data[task_index];
+            task.stack_addr = stack_addr;
+            task.stack_size = stack_size;
+            task.tss.esp = esp as i32;
+            task.tss.eip = entry.eip();
+            task.tss.es = 1 * 8;
+            task.tss.cs = 2 * 8;
+            task.tss.ss = 1 * 8;
+            task.tss.ds = 1 * 8;
+            task.tss.fs = 1 * 8;
+            task.tss.gs = 1 * 8;
+        }
+        let ret = unsafe { &mut *(esp as *mut u32) };
+        *ret = task_return as u32;
+        F::write_args(args, esp);
+        self.run(task_index, level, priority);
+        Ok(task_index)
+    }
+
     pub fn run(&mut self, task_index: usize, level_i32: i32, priority: i32) {
         let task = self.tasks_data[task_index];
+        if task.flag == TaskFlag::AVAILABLE {
+            // 終了したタスクのFIFOに書き込まれても起こさない
+            return;
+        }
         let level: usize;
         if level_i32 < 0 {
             level = task.level;
@@ -276,6 +432,77 @@ impl TaskManager {
             }
         }
     }
+
+    // タスクを終了させ，スタックとタイマを解放してAVAILABLEに戻す
+    // 今動いているタスク自身を指定した場合は次のタスクに切り替わり，戻ってこない
+    // アプリ実行中のタスクはアプリのセグメントなどを片付けられないので終了させない
+    pub fn free(&mut self, task_index: usize, memman: &mut MemMan) -> Result<(), &'static str> {
+        let task = self.tasks_data[task_index];
+        if task.flag == TaskFlag::AVAILABLE {
+            return Err("TASK IS NOT ALLOCATED");
+        }
+        if task.tss.ss0 != 0 {
+            return Err("TASK IS RUNNING AN APPLICATION");
+        }
+        let eflags = load_eflags();
+        // スタックを解放してから切り替えるまでの間に他のタスクが動かないようにする
+        cli();
+        let now_index = self.now_index();
+        if task.flag == TaskFlag::RUNNING {
+            self.remove_task(task_index);
+            self.lv_change = true;
+        }
+        if task.fifo_addr != 0 {
+            // このタスクのFIFOはスタック上にあるので，そこに書き込むタイマは全部止める
+            TIMER_MANAGER.lock().free_fifo_timers(task.fifo_addr);
+        }
+        if task.stack_addr != 0 {
+            memman.free_4k(task.stack_addr as u32, task.stack_size).unwrap();
+        }
+        // コンソールタスクが読み込んだFATと，開いたままのファイル
+        if task.fat_addr != 0 {
+            memman.free_4k(task.fat_addr as u32, (MAX_FAT * 4) as u32).unwrap();
+        }
+        for fh in self.tasks_data[task_index].fhandles.iter_mut() {
+            if fh.buf_addr != 0 {
+                fh.close(memman);
+            }
+        }
+        // GDTに登録したTSSとLDTはそのまま使い回す
+        {
+            let mut freed = &mut self.tasks_data[task_index];
+            let select = freed.select;
+            let ldtr = freed.tss.ldtr;
+            *freed = Task::new();
+            freed.select = select;
+            freed.tss.ldtr = ldtr;
+        }
+        if task_index == now_index {
+            self.switchsub();
+            let next_task = self.tasks_data[self.now_index()];
+            farjmp(0, next_task.select);
+            // AVAILABLEになったタスクにはもう切り替わらない
+            loop {
+                hlt();
+            }
+        }
+        store_eflags(eflags);
+        Ok(())
+    }
+
+    // 今動いているタスクを終了させる
+    pub fn exit(&mut self, memman: &mut MemMan) -> ! {
+        let task_index = self.now_index();
+        self.free(task_index, memman).unwrap();
+        unreachable!();
+    }
+}
+
+// spawnしたタスクの開始関数から戻ってきたところ
+extern "C" fn task_return() {
+    let task_manager = unsafe { &mut *(TASK_MANAGER_ADDR as *mut TaskManager) };
+    let memman = unsafe { &mut *(MEMMAN_ADDR as *mut MemMan) };
+    task_manager.exit(memman);
 }
 
 pub extern "C" fn task_idle() {
@@ -1,6 +1,11 @@
+use core::cell::Cell;
 use core::cmp::{max, min};
+use core::mem::size_of;
+use core::ptr::{copy_nonoverlapping, write_bytes};
+use core::slice;
 
-use crate::vga::{Color, SCREEN_HEIGHT, SCREEN_WIDTH, VRAM_ADDR};
+use crate::asm::{cli, load_eflags, store_eflags};
+use crate::vga::{Color, SCREEN_FORMAT, SCREEN_HEIGHT, SCREEN_WIDTH, VRAM_ADDR};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SheetFlag {
@@ -18,6 +23,15 @@ pub struct Sheet {
     pub transparent: Option<Color>,
     pub z: Option<usize>, // 重ねあわせたときの高さ
     pub flag: SheetFlag,
+    pub task: Option<usize>, // このシートへのキー入力を受け取るタスクのindex
+    pub app: bool,           // アプリが開いたウィンドウ(アプリの終了時に閉じる)
+    // 大きさを変えられるウィンドウ
+    // バッファはMemManのalloc_4kでwidth * heightバイト確保したもので，taskが確保しなおす
+    // 今はコンソールだけ．アプリのウィンドウはバッファがアプリのセグメントにあり，
+    // 大きさが変わったことをアプリに知らせるAPIもないので変えられない
+    pub resizable: bool,
+    pub resize_to: Option<(i32, i32)>, // taskに頼んだ新しい大きさ
+    pub restore: Option<(i32, i32, i32, i32)>, // 最大化する前の位置と大きさ
 }
 
 impl Sheet {
@@ -31,6 +45,11 @@ impl Sheet {
             transparent: None,
             z: None,
             flag: SheetFlag::AVAILABLE,
+            task: None,
+            app: false,
+            resizable: false,
+            resize_to: None,
+            restore: None,
         }
     }
 
@@ -42,23 +61,85 @@ impl Sheet {
     }
 }
 
-const MAX_SHEETS: usize = 256;
+// 重ね合わせ用のマップに書くシートのindexの型
+// MAX_SHEETSを増やすときはすべてのindexが入るようにこの型も広げる
+pub type SheetId = u16;
+
+const MAX_SHEETS: usize = 1024;
+// 一番大きいindex(MAX_SHEETS - 1)がSheetIdに入らなければ，配列の長さが合わずにコンパイルエラーになる
+#[allow(dead_code)]
+const SHEET_ID_FITS: [(); 0] = [(); (MAX_SHEETS - 1 > SheetId::max_value() as usize) as usize];
+const MAX_DIRTY: usize = 32;
+
+// 書き直しが必要な画面上の範囲(x1, y1は含まない)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirtyRect {
+    pub x0: i32,
+    pub y0: i32,
+    pub x1: i32,
+    pub y1: i32,
+}
+
+impl DirtyRect {
+    // 重なっているか接していれば1つにまとめられる
+    fn touches(&self, other: &DirtyRect) -> bool {
+        self.x0 <= other.x1 && other.x0 <= self.x1 && self.y0 <= other.y1 && other.y0 <= self.y1
+    }
+
+    fn union(&self, other: &DirtyRect) -> DirtyRect {
+        DirtyRect {
+            x0: min(self.x0, other.x0),
+            y0: min(self.y0, other.y0),
+            x1: max(self.x1, other.x1),
+            y1: max(self.y1, other.y1),
+        }
+    }
+}
 
 pub struct SheetManager {
     pub z_max: Option<usize>,             // 一番上のSheetのz
+    pub mouse: Option<usize>,             // マウスカーソルのシート(マップには書かない)
     pub map_addr: i32,                    // 重ね合わせ計算用のマップをもつ
     pub sheets: [usize; MAX_SHEETS],      // sheets_data上のindexを保持する
     pub sheets_data: [Sheet; MAX_SHEETS], // sheetデータの実体
+    pub windows: [usize; MAX_SHEETS],     // キー入力を受け付けるウィンドウのindex(開いた順)
+    pub window_count: usize,
+    // コンポジタモード用のバックバッファ(0なら直接VRAMに描く)
+    pub back_addr: usize,
+    dirty: [Cell<DirtyRect>; MAX_DIRTY], // まだVRAMに転送していない範囲
+    dirty_count: Cell<usize>,
 }
 
 impl SheetManager {
-    pub fn new(map_addr: i32) -> SheetManager {
-        SheetManager {
-            z_max: None,
-            map_addr,
-            sheets: [0; MAX_SHEETS],
-            sheets_data: [Sheet::new(); MAX_SHEETS],
+    // SheetManagerは大きいのでスタックに作らず，確保した領域をそのまま初期化する
+    pub fn init(&mut self, map_addr: i32) {
+        self.z_max = None;
+        self.mouse = None;
+        self.map_addr = map_addr;
+        // 確保しただけのマップには，まだないシートのindexが入っているかもしれない
+        unsafe {
+            write_bytes(map_addr as *mut u8, 0, SheetManager::map_size() as usize);
+        }
+        for i in 0..MAX_SHEETS {
+            self.sheets[i] = 0;
+            self.sheets_data[i] = Sheet::new();
+            self.windows[i] = 0;
         }
+        self.window_count = 0;
+        self.back_addr = 0;
+        self.dirty_count.set(0);
+    }
+
+    // コンポジタモードにする．back_addrは画面と同じ大きさ・形式(SCREEN_FORMAT)のバッファ
+    // 以降のrefreshはバックバッファへの描画を予約するだけになり，flushでまとめてVRAMに転送する
+    pub fn enable_compositor(&mut self, back_addr: usize) {
+        self.back_addr = back_addr;
+        self.add_dirty(0, 0, *SCREEN_WIDTH as i32, *SCREEN_HEIGHT as i32);
+    }
+
+    // 重ね合わせ用のマップに必要なバイト数
+    pub fn map_size() -> u32 {
+        *SCREEN_WIDTH as u32 * *SCREEN_HEIGHT as u32 * size_of::<SheetId>() as u32
     }
 
     pub fn set_buf(
@@ -84,12 +165,19 @@ impl SheetManager {
                 let mut sheet = &mut self.sheets_data[i];
                 sheet.flag = SheetFlag::USED;
                 sheet.z = None;
+                sheet.task = None;
+                sheet.app = false;
+                sheet.resizable = false;
+                sheet.resize_to = None;
+                sheet.restore = None;
                 return Some(i);
             }
         }
         None
     }
 
+    // マウスのシートはマップに書かない．マップはマウスの下に見えているシートを指し，
+    // window_atでクリックされたウィンドウを調べるのに使える(マウスはcomposeが最後に上に描く)
     pub fn refresh_map(&self, x0: i32, y0: i32, x1: i32, y1: i32, z0: i32) {
         if self.z_max.is_none() {
             return;
@@ -100,6 +188,9 @@ impl SheetManager {
         let y1 = min(y1, *SCREEN_HEIGHT as i32);
         for h in (z0 as usize)..=self.z_max.unwrap() {
             let si = self.sheets[h as usize];
+            if Some(si) == self.mouse {
+                continue;
+            }
             let sheet = &self.sheets_data[si];
             let bx0 = if x0 > sheet.x { x0 - sheet.x } else { 0 } as usize;
             let by0 = if y0 > sheet.y { y0 - sheet.y } else { 0 } as usize;
@@ -113,6 +204,26 @@ impl SheetManager {
             } else {
                 0
             } as usize;
+            if sheet.transparent.is_none() {
+                // 透明色のないシートは範囲をまるごと自分のindexで埋めればよい
+                if bx0 >= bx1 {
+                    continue;
+                }
+                for by in by0..by1 {
+                    let vy = (sheet.y + by as i32) as usize;
+                    let vx0 = (sheet.x + bx0 as i32) as usize;
+                    let row = unsafe {
+                        slice::from_raw_parts_mut(
+                            (self.map_addr as *mut SheetId).add(vy * *SCREEN_WIDTH as usize + vx0),
+                            bx1 - bx0,
+                        )
+                    };
+                    for id in row.iter_mut() {
+                        *id = si as SheetId;
+                    }
+                }
+                continue;
+            }
             for by in by0..by1 {
                 let vy = (sheet.y + by as i32) as usize;
                 for bx in bx0..bx1 {
@@ -121,10 +232,10 @@ impl SheetManager {
                     let c = unsafe { *((sheet.buf_addr + by * width + bx) as *const Color) };
                     if Some(c) != sheet.transparent {
                         let ptr = unsafe {
-                            &mut *((self.map_addr as *mut u8)
+                            &mut *((self.map_addr as *mut SheetId)
                                 .offset(vy as isize * *SCREEN_WIDTH as isize + vx as isize))
                         };
-                        *ptr = si as u8;
+                        *ptr = si as SheetId;
                     }
                 }
             }
@@ -139,7 +250,21 @@ impl SheetManager {
         let y0 = max(0, y0);
         let x1 = min(x1, *SCREEN_WIDTH as i32);
         let y1 = min(y1, *SCREEN_HEIGHT as i32);
+        if self.back_addr != 0 {
+            self.add_dirty(x0, y0, x1, y1);
+        } else {
+            self.compose(x0, y0, x1, y1, z0, z1, *VRAM_ADDR);
+            // マップにないマウスは下のシートで上書きされるので描き直す
+            if let Some(mz) = self.mouse.and_then(|mouse| self.sheets_data[mouse].z) {
+                if z1 < mz as i32 {
+                    self.compose(x0, y0, x1, y1, mz as i32, mz as i32, *VRAM_ADDR);
+                }
+            }
+        }
+    }
 
+    // z0からz1までのシートを重ね合わせてdst_addr(画面と同じ大きさ・形式のバッファ)に描く
+    fn compose(&self, x0: i32, y0: i32, x1: i32, y1: i32, z0: i32, z1: i32, dst_addr: usize) {
         for h in (z0 as usize)..=(z1 as usize) {
             let si = self.sheets[h as usize];
             let sheet = &self.sheets_data[si];
@@ -155,24 +280,151 @@ impl SheetManager {
             } else {
                 0
             } as usize;
+            if bx0 >= bx1 {
+                continue;
+            }
+            // マップがこのシートを指している連続した部分を1行ずつまとめて転送する
+            // 透明色のないシートなら隠れていない限り1行が1回の転送で済む
+            // マウスのシートはマップに載っていないので，透明色でない部分を転送する
+            let scrnx = *SCREEN_WIDTH as usize;
+            let format = *SCREEN_FORMAT;
+            let bpp = format.bytes_per_pixel();
+            let width = sheet.width as usize;
+            let id = si as SheetId;
             for by in by0..by1 {
                 let vy = (sheet.y + by as i32) as usize;
-                for bx in bx0..bx1 {
-                    let vx = (sheet.x + bx as i32) as usize;
-                    let width = sheet.width as usize;
-                    let map_si = unsafe {
-                        *((self.map_addr as isize
-                            + vy as isize * *SCREEN_WIDTH as isize
-                            + vx as isize) as *const u8)
-                    };
-                    if si as u8 == map_si {
-                        let c = unsafe { *((sheet.buf_addr + by * width + bx) as *const Color) };
-                        let ptr = unsafe {
-                            &mut *((*VRAM_ADDR as *mut u8)
-                                .offset(vy as isize * *SCREEN_WIDTH as isize + vx as isize))
-                        };
-                        *ptr = c as u8;
+                let vx0 = (sheet.x + bx0 as i32) as usize;
+                let map_row = unsafe {
+                    slice::from_raw_parts(
+                        (self.map_addr as *const SheetId).add(vy * scrnx + vx0),
+                        bx1 - bx0,
+                    )
+                };
+                let buf_row = unsafe {
+                    slice::from_raw_parts(
+                        (sheet.buf_addr + by * width + bx0) as *const Color,
+                        bx1 - bx0,
+                    )
+                };
+                let visible = |i: usize| {
+                    if Some(si) == self.mouse {
+                        Some(buf_row[i]) != sheet.transparent
+                    } else {
+                        map_row[i] == id
                     }
+                };
+                let mut i = 0;
+                while i < map_row.len() {
+                    if !visible(i) {
+                        i += 1;
+                        continue;
+                    }
+                    let start = i;
+                    while i < map_row.len() && visible(i) {
+                        i += 1;
+                    }
+                    format.convert_row(
+                        sheet.buf_addr + by * width + bx0 + start,
+                        dst_addr + (vy * scrnx + vx0 + start) * bpp,
+                        i - start,
+                    );
+                }
+            }
+        }
+    }
+
+    // 画面全体のマップを作り直して重ね合わせる(benchコマンドで速さを測るため)
+    // コンポジタモードならVRAMへの転送までを行う
+    pub fn redraw_screen(&self) {
+        let z_max = match self.z_max {
+            Some(z) => z as i32,
+            None => return,
+        };
+        let scrnx = *SCREEN_WIDTH as i32;
+        let scrny = *SCREEN_HEIGHT as i32;
+        self.refresh_map(0, 0, scrnx, scrny, 0);
+        if self.back_addr != 0 {
+            self.compose(0, 0, scrnx, scrny, 0, z_max, self.back_addr);
+            unsafe {
+                copy_nonoverlapping(
+                    self.back_addr as *const u8,
+                    *VRAM_ADDR as *mut u8,
+                    (scrnx * scrny) as usize * SCREEN_FORMAT.bytes_per_pixel(),
+                );
+            }
+        } else {
+            self.compose(0, 0, scrnx, scrny, 0, z_max, *VRAM_ADDR);
+        }
+    }
+
+    // 書き直しが必要な範囲を記録する．重なる範囲はまとめておく
+    // 他のタスクからもrefreshされるので割り込みを止めて操作する
+    fn add_dirty(&self, x0: i32, y0: i32, x1: i32, y1: i32) {
+        if x0 >= x1 || y0 >= y1 {
+            return;
+        }
+        let eflags = load_eflags();
+        cli();
+        let mut rect = DirtyRect { x0, y0, x1, y1 };
+        let mut n = self.dirty_count.get();
+        let mut i = 0;
+        while i < n {
+            let d = self.dirty[i].get();
+            if d.touches(&rect) {
+                // まとめると大きくなって他と重なるかもしれないので最初から見直す
+                rect = rect.union(&d);
+                n -= 1;
+                self.dirty[i].set(self.dirty[n].get());
+                i = 0;
+            } else {
+                i += 1;
+            }
+        }
+        if n == MAX_DIRTY {
+            // 記録しきれないので全部1つにまとめる
+            for d in self.dirty.iter() {
+                rect = rect.union(&d.get());
+            }
+            n = 0;
+        }
+        self.dirty[n].set(rect);
+        self.dirty_count.set(n + 1);
+        store_eflags(eflags);
+    }
+
+    // 記録しておいた範囲をバックバッファに重ね合わせてからVRAMに転送する
+    // コンポジタモードのときに一定間隔(1フレームごと)で呼ぶ
+    pub fn flush(&self) {
+        if self.back_addr == 0 {
+            return;
+        }
+        let eflags = load_eflags();
+        cli();
+        let n = self.dirty_count.get();
+        let mut rects = [DirtyRect { x0: 0, y0: 0, x1: 0, y1: 0 }; MAX_DIRTY];
+        for i in 0..n {
+            rects[i] = self.dirty[i].get();
+        }
+        self.dirty_count.set(0);
+        store_eflags(eflags);
+
+        let z_max = match self.z_max {
+            Some(z) => z as i32,
+            None => return,
+        };
+        let scrnx = *SCREEN_WIDTH as usize;
+        let bpp = SCREEN_FORMAT.bytes_per_pixel();
+        for r in rects[..n].iter() {
+            self.compose(r.x0, r.y0, r.x1, r.y1, 0, z_max, self.back_addr);
+            let w = (r.x1 - r.x0) as usize * bpp;
+            for y in r.y0 as usize..r.y1 as usize {
+                let offset = (y * scrnx + r.x0 as usize) * bpp;
+                unsafe {
+                    copy_nonoverlapping(
+                        (self.back_addr + offset) as *const u8,
+                        (*VRAM_ADDR + offset) as *mut u8,
+                        w,
+                    );
                 }
             }
         }
@@ -246,11 +498,13 @@ impl SheetManager {
             } else {
                 // 非表示 -> 表示
                 if let Some(z) = oz {
-                    let zmax = if let Some(zmax) = self.z_max { zmax } else { 0 };
-                    for h in z..zmax {
-                        self.sheets[h + 1] = self.sheets[h];
-                        let mut sh = &mut self.sheets_data[self.sheets[h + 1]];
-                        sh.z = Some(h + 1);
+                    if let Some(zmax) = self.z_max {
+                        // 上から順にずらして場所をあける
+                        for h in (z..=zmax).rev() {
+                            self.sheets[h + 1] = self.sheets[h];
+                            let mut sh = &mut self.sheets_data[self.sheets[h + 1]];
+                            sh.z = Some(h + 1);
+                        }
                     }
                     self.sheets[z] = sheet_index;
                     if let Some(zmax) = self.z_max {
@@ -296,6 +550,21 @@ impl SheetManager {
         }
     }
 
+    // (x, y)にあるウィンドウのindex．背景(一番下)とマウスは除く
+    // マップにはマウスを書かないので，マウスカーソルの位置でもその下のシートがわかる
+    pub fn window_at(&self, x: i32, y: i32) -> Option<usize> {
+        if x < 0 || *SCREEN_WIDTH as i32 <= x || y < 0 || *SCREEN_HEIGHT as i32 <= y {
+            return None;
+        }
+        let si = unsafe {
+            *(self.map_addr as *const SheetId).add(y as usize * *SCREEN_WIDTH as usize + x as usize)
+        } as usize;
+        match self.sheets_data.get(si)?.z {
+            Some(z) if 0 < z && Some(si) != self.mouse => Some(si),
+            _ => None,
+        }
+    }
+
     pub fn get_new_point(&self, sheet_index: usize, dx: i32, dy: i32) -> (i32, i32) {
         let scrnx = *SCREEN_WIDTH as i32;
         let scrny = *SCREEN_HEIGHT as i32;
@@ -351,7 +620,82 @@ impl SheetManager {
         if sheet.z.is_some() {
             self.updown(sheet_index, None);
         }
+        self.remove_window(sheet_index);
         let mut sheet = &mut self.sheets_data[sheet_index];
         sheet.flag = SheetFlag::AVAILABLE;
+        sheet.task = None;
+        sheet.app = false;
+        sheet.resizable = false;
+        sheet.resize_to = None;
+        sheet.restore = None;
+    }
+
+    // 大きさの違う新しいバッファに取り替えて，古いバッファの番地を返す
+    // 新しいバッファには描き終えてから渡す(取り替えた時点で前後の範囲を描き直す)
+    pub fn replace_buf(
+        &mut self,
+        sheet_index: usize,
+        buf_addr: usize,
+        width: i32,
+        height: i32,
+    ) -> usize {
+        let old = self.sheets_data[sheet_index];
+        {
+            let sheet = &mut self.sheets_data[sheet_index];
+            sheet.buf_addr = buf_addr;
+            sheet.width = width;
+            sheet.height = height;
+        }
+        if let Some(z) = old.z {
+            let x1 = old.x + max(old.width, width);
+            let y1 = old.y + max(old.height, height);
+            self.refresh_map(old.x, old.y, x1, y1, 0);
+            self.refresh_part(old.x, old.y, x1, y1, 0, z as i32);
+        }
+        old.buf_addr
+    }
+
+    // キー入力を受け付けるウィンドウとして登録する
+    // taskはキー入力を送る先のタスク(Noneなら呼び出し側が自分で処理する)
+    pub fn add_window(&mut self, sheet_index: usize, task: Option<usize>) {
+        self.sheets_data[sheet_index].task = task;
+        if self.window_count < MAX_SHEETS {
+            self.windows[self.window_count] = sheet_index;
+            self.window_count += 1;
+        }
+    }
+
+    fn remove_window(&mut self, sheet_index: usize) {
+        if let Some(i) = self.windows[..self.window_count]
+            .iter()
+            .position(|&si| si == sheet_index)
+        {
+            // 後ろを詰めて開いた順を保つ
+            for j in i..self.window_count - 1 {
+                self.windows[j] = self.windows[j + 1];
+            }
+            self.window_count -= 1;
+        }
+    }
+
+    // sheet_indexの次にキー入力を受け付けるウィンドウ(最後まで来たら最初に戻る)
+    pub fn next_window(&self, sheet_index: usize) -> Option<usize> {
+        if self.window_count == 0 {
+            return None;
+        }
+        let i = self.windows[..self.window_count]
+            .iter()
+            .position(|&si| si == sheet_index)
+            .map_or(0, |i| (i + 1) % self.window_count);
+        Some(self.windows[i])
+    }
+
+    // キー入力を受け付けるウィンドウのうち一番上に表示されているもの
+    pub fn top_window(&self) -> Option<usize> {
+        self.windows[..self.window_count]
+            .iter()
+            .filter(|&&si| self.sheets_data[si].z.is_some())
+            .max_by_key(|&&si| self.sheets_data[si].z)
+            .cloned()
     }
 }
@@ -22,8 +22,9 @@ pub struct Timer {
     pub timeout: u32,
     pub flags: TimerFlag,
     pub fifo: usize,
-    pub data: u8,
+    pub data: u32,
     pub next: Option<usize>,
+    pub task: Option<usize>, // アプリが確保したタイマの場合，そのタスクのindex
 }
 
 impl Timer {
@@ -34,6 +35,7 @@ impl Timer {
             fifo: 0,
             data: 0,
             next: None,
+            task: None,
         }
     }
 }
@@ -60,6 +62,7 @@ impl TimerManager {
             fifo: 0,
             data: 0,
             next: None,
+            task: None,
         };
         tm
     }
@@ -68,6 +71,7 @@ impl TimerManager {
         for i in 0..MAX_TIMER {
             if self.timers_data[i].flags == TimerFlag::AVAILABLE {
                 self.timers_data[i].flags = TimerFlag::ALLOC;
+                self.timers_data[i].task = None;
                 return Ok(i);
             }
         }
@@ -77,9 +81,61 @@ impl TimerManager {
     pub fn free(&mut self, i: usize) {
         let mut timer = &mut self.timers_data[i];
         timer.flags = TimerFlag::AVAILABLE;
+        timer.task = None;
     }
 
-    pub fn init_timer(&mut self, index: usize, fifo: usize, data: u8) {
+    // 動作中のタイマを止める．止めた場合はtrueを返す
+    pub fn cancel(&mut self, index: usize) -> bool {
+        let eflags = asm::load_eflags();
+        asm::cli();
+        if self.timers_data[index].flags != TimerFlag::USING {
+            asm::store_eflags(eflags);
+            return false;
+        }
+        let next = self.timers_data[index].next;
+        if self.t0 == Some(index) {
+            // 先頭だったので次のタイマを先頭にする
+            self.t0 = next;
+            if let Some(n) = next {
+                self.next_time = self.timers_data[n].timeout;
+            }
+        } else {
+            // 一つ前のタイマを探してつなぎかえる
+            let mut t_index = self.t0.unwrap();
+            while self.timers_data[t_index].next != Some(index) {
+                t_index = self.timers_data[t_index].next.unwrap();
+            }
+            self.timers_data[t_index].next = next;
+        }
+        self.timers_data[index].flags = TimerFlag::ALLOC;
+        asm::store_eflags(eflags);
+        true
+    }
+
+    // アプリが確保したタイマを全て止めて解放する
+    pub fn free_task_timers(&mut self, task_index: usize) {
+        for i in 0..MAX_TIMER {
+            let timer = self.timers_data[i];
+            if timer.flags != TimerFlag::AVAILABLE && timer.task == Some(task_index) {
+                self.cancel(i);
+                self.free(i);
+            }
+        }
+    }
+
+    // 指定したFIFOに書き込むタイマを全て止めて解放する
+    // FIFOを持つタスクが終了するときに使う
+    pub fn free_fifo_timers(&mut self, fifo_addr: usize) {
+        for i in 0..MAX_TIMER {
+            let timer = self.timers_data[i];
+            if timer.flags != TimerFlag::AVAILABLE && timer.fifo == fifo_addr {
+                self.cancel(i);
+                self.free(i);
+            }
+        }
+    }
+
+    pub fn init_timer(&mut self, index: usize, fifo: usize, data: u32) {
         let mut timer = &mut self.timers_data[index];
         timer.fifo = fifo;
         timer.data = data;
@@ -88,7 +144,8 @@ impl TimerManager {
     pub fn set_time(&mut self, index: usize, timeout: u32) {
         {
             let mut timer = &mut self.timers_data[index];
-            timer.timeout = timeout + self.count;
+            // アプリが大きな時間を指定してもあふれないようにする
+            timer.timeout = timeout.saturating_add(self.count);
             timer.flags = TimerFlag::USING;
         }
         if self.t0.is_none() {
@@ -135,6 +192,16 @@ lazy_static! {
     pub static ref TIMER_MANAGER: Mutex<TimerManager> = Mutex::new(TimerManager::new());
 }
 
+// タイマ割り込み(inthandler20)もTIMER_MANAGERをロックするので，割り込みを止めてから使う
+// ロックしたまま割り込まれると，inthandler20がロックを待ち続けて止まってしまう
+pub fn with_timer_manager<R>(f: impl FnOnce(&mut TimerManager) -> R) -> R {
+    let eflags = asm::load_eflags();
+    asm::cli();
+    let r = f(&mut TIMER_MANAGER.lock());
+    asm::store_eflags(eflags);
+    r
+}
+
 pub fn init_pit() {
     asm::out8(PIT_CTRL, 0x34);
     asm::out8(PIT_CNT0, 0x9c);
@@ -163,8 +230,9 @@ pub extern "C" fn inthandler20() {
         let mut t = &mut tm.timers_data[t_index];
         t.flags = TimerFlag::ALLOC;
         if t_index != unsafe { crate::multi_task::MT_TIMER_INDEX } {
+            // FIFOがいっぱいなら捨てる(アプリがタイマを大量に使っても割り込みの中で止まらない)
             let fifo = unsafe { &*(t.fifo as *mut Fifo) };
-            fifo.put(t.data as u32).unwrap();
+            fifo.put(t.data).ok();
         } else {
             need_taskswitch = true;
         }
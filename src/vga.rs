@@ -24,26 +24,196 @@ const COLOR_PALETTE: [[u8; 3]; 16] = [
     [0x84, 0x84, 0x84], /* 15:暗い灰色 */
 ];
 
-#[allow(dead_code)]
+// 16色の後ろに置く6x6x6の色の立方体(R, G, Bそれぞれ0, 51, ..., 255の6段階)
+const CUBE_BASE: usize = 16;
+const CUBE_STEPS: u8 = 6;
+pub const PALETTE_SIZE: usize = CUBE_BASE + 216;
+
+// パレット番号の色
+fn palette_rgb(index: u8) -> [u8; 3] {
+    let index = index as usize;
+    if index < CUBE_BASE {
+        COLOR_PALETTE[index]
+    } else if index < PALETTE_SIZE {
+        let i = (index - CUBE_BASE) as u8;
+        [
+            i % CUBE_STEPS * 51,
+            i / CUBE_STEPS % CUBE_STEPS * 51,
+            i / (CUBE_STEPS * CUBE_STEPS) * 51,
+        ]
+    } else {
+        [0, 0, 0]
+    }
+}
+
+// 色(パレット番号)．シートのバッファにはこの1バイトがそのまま入る
+// 名前のついた16色の他に，Rgbから一番近い色を選んで使える
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct Color(pub u8);
+
+#[allow(dead_code, non_upper_case_globals)]
+impl Color {
+    pub const Black: Color = Color(0);
+    pub const LightRed: Color = Color(1);
+    pub const LightGreen: Color = Color(2);
+    pub const LightYellow: Color = Color(3);
+    pub const LightBlue: Color = Color(4);
+    pub const LightPurple: Color = Color(5);
+    pub const LightCyan: Color = Color(6);
+    pub const White: Color = Color(7);
+    pub const LightGray: Color = Color(8);
+    pub const DarkRed: Color = Color(9);
+    pub const DarkGreen: Color = Color(10);
+    pub const DarkYellow: Color = Color(11);
+    pub const DarkBlue: Color = Color(12);
+    pub const DarkPurple: Color = Color(13);
+    pub const DarkCyan: Color = Color(14);
+    pub const DarkGray: Color = Color(15);
+}
+
+impl Color {
+    // パレット番号からColorを得る．範囲外の番号は黒にする
+    pub fn from_index(index: u8) -> Color {
+        if (index as usize) < PALETTE_SIZE {
+            Color(index)
+        } else {
+            Color::Black
+        }
+    }
+
+    pub fn rgb(self) -> Rgb {
+        let [r, g, b] = palette_rgb(self.0);
+        Rgb { r, g, b }
+    }
+}
+
+// 任意の色．パレットには一番近い色に置き換えて描く
+// シートのバッファはパレット番号なので，画面が16/32ビットのモードでもこの置き換えは必要
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u8)]
-pub enum Color {
-    Black = 0,
-    LightRed = 1,
-    LightGreen = 2,
-    LightYellow = 3,
-    LightBlue = 4,
-    LightPurple = 5,
-    LightCyan = 6,
-    White = 7,
-    LightGray = 8,
-    DarkRed = 9,
-    DarkGreen = 10,
-    DarkYellow = 11,
-    DarkBlue = 12,
-    DarkPurple = 13,
-    DarkCyan = 14,
-    DarkGray = 15,
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const fn new(r: u8, g: u8, b: u8) -> Rgb {
+        Rgb { r, g, b }
+    }
+
+    // 0xRRGGBB から
+    pub fn from_u32(rgb: u32) -> Rgb {
+        Rgb::new((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)
+    }
+
+    fn distance(self, other: Rgb) -> u32 {
+        let d = |a: u8, b: u8| {
+            let d = a as i32 - b as i32;
+            (d * d) as u32
+        };
+        d(self.r, other.r) + d(self.g, other.g) + d(self.b, other.b)
+    }
+
+    // パレットの中で一番近い色
+    // 立方体の中では各成分を一番近い段階に丸めたものが最も近いので，それと16色を比べる
+    pub fn to_color(self) -> Color {
+        let step = |v: u8| (v as u32 + 25) / 51;
+        let cube = Color(
+            (CUBE_BASE as u32 + step(self.r) + step(self.g) * 6 + step(self.b) * 36) as u8,
+        );
+        let mut best = cube;
+        let mut best_d = self.distance(cube.rgb());
+        for i in 0..CUBE_BASE as u8 {
+            let d = self.distance(Color(i).rgb());
+            if d < best_d {
+                best = Color(i);
+                best_d = d;
+            }
+        }
+        best
+    }
+}
+
+// 画面(VRAM)の1ピクセルの形式
+// シートのバッファは常に1ピクセル1バイトのパレット番号で，VRAMに描くときにこの形式に変換する
+// そのため16/32ビットのモードでも出せるのはパレットの色だけで，色数は8ビットのモードと変わらない
+// (asmheadも8ビットのモードを先に試し，使えないときだけ16/32ビットのモードにする)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Indexed8, // 8ビット パレット番号
+    Rgb565,   // 16ビット ハイカラー
+    Xrgb8888, // 32ビット トゥルーカラー
+}
+
+impl PixelFormat {
+    // asmheadがVMODEに書いたビット数から決める
+    fn from_vmode(vmode: u8) -> PixelFormat {
+        match vmode {
+            16 => PixelFormat::Rgb565,
+            32 => PixelFormat::Xrgb8888,
+            _ => PixelFormat::Indexed8,
+        }
+    }
+
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Indexed8 => 1,
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Xrgb8888 => 4,
+        }
+    }
+
+    // パレット番号をこの形式のピクセル値にする．パレットにない番号は黒にする
+    pub fn encode(self, index: u8) -> u32 {
+        if self == PixelFormat::Indexed8 {
+            return index as u32;
+        }
+        let [r, g, b] = palette_rgb(index);
+        let (r, g, b) = (r as u32, g as u32, b as u32);
+        match self {
+            PixelFormat::Rgb565 => ((r >> 3) << 11) | ((g >> 2) << 5) | (b >> 3),
+            _ => (r << 16) | (g << 8) | b,
+        }
+    }
+
+    // bufのindex番目のピクセルにcolorを書く
+    pub fn write(self, buf: usize, index: usize, color: Color) {
+        unsafe {
+            match self {
+                PixelFormat::Indexed8 => *((buf + index) as *mut u8) = color.0,
+                PixelFormat::Rgb565 => {
+                    *((buf + index * 2) as *mut u16) = self.encode(color.0) as u16
+                }
+                PixelFormat::Xrgb8888 => {
+                    *((buf + index * 4) as *mut u32) = self.encode(color.0)
+                }
+            }
+        }
+    }
+
+    // パレット番号の並び(src)をlenピクセル分この形式に変換してdstに書く
+    pub fn convert_row(self, src: usize, dst: usize, len: usize) {
+        unsafe {
+            match self {
+                PixelFormat::Indexed8 => {
+                    core::ptr::copy_nonoverlapping(src as *const u8, dst as *mut u8, len)
+                }
+                PixelFormat::Rgb565 => {
+                    for i in 0..len {
+                        let c = *((src + i) as *const u8);
+                        *((dst as *mut u16).add(i)) = SCREEN_COLORS[c as usize] as u16;
+                    }
+                }
+                PixelFormat::Xrgb8888 => {
+                    for i in 0..len {
+                        let c = *((src + i) as *const u8);
+                        *((dst as *mut u32).add(i)) = SCREEN_COLORS[c as usize];
+                    }
+                }
+            }
+        }
+    }
 }
 
 pub const MAX_BLOCK_SIZE: usize = 16;
@@ -52,42 +222,56 @@ lazy_static! {
     pub static ref SCREEN_WIDTH: i16 = unsafe { *(0x0ff4 as *const i16) };
     pub static ref SCREEN_HEIGHT: i16 = unsafe { *(0x0ff6 as *const i16) };
     pub static ref VRAM_ADDR: usize = unsafe { *(0xff8 as *const usize) };
+    pub static ref SCREEN_FORMAT: PixelFormat =
+        PixelFormat::from_vmode(unsafe { *(0x0ff2 as *const u8) });
+    // パレット番号ごとの画面上のピクセル値(変換のたびに計算しないように)
+    static ref SCREEN_COLORS: [u32; 256] = {
+        let mut colors = [0; 256];
+        for i in 0..256 {
+            colors[i] = SCREEN_FORMAT.encode(i as u8);
+        }
+        colors
+    };
 }
 
 pub fn init_palette() {
+    // パレットを使うのは8ビットカラーのときだけ
+    if *SCREEN_FORMAT != PixelFormat::Indexed8 {
+        return;
+    }
     let eflags = asm::load_eflags();
     asm::cli();
     asm::out8(0x03c8, 0);
-    for i in 0..16 {
+    for i in 0..PALETTE_SIZE {
+        let [r, g, b] = palette_rgb(i as u8);
         // 書き込むときは上位2ビットを0にしないといけない。See: http://oswiki.osask.jp/?VGA#o2d4bfd3
-        asm::out8(0x03c9, COLOR_PALETTE[i][0] / 4);
-        asm::out8(0x03c9, COLOR_PALETTE[i][1] / 4);
-        asm::out8(0x03c9, COLOR_PALETTE[i][2] / 4);
+        asm::out8(0x03c9, r / 4);
+        asm::out8(0x03c9, g / 4);
+        asm::out8(0x03c9, b / 4);
     }
     asm::store_eflags(eflags);
 }
 
 pub fn init_screen(buf: usize) {
-    use Color::*;
     let xsize = *SCREEN_WIDTH as isize;
     let ysize = *SCREEN_HEIGHT as isize;
 
-    boxfill(buf, xsize, DarkCyan, 0, 0, xsize - 1, ysize - 29);
-    boxfill(buf, xsize, LightGray, 0, ysize - 28, xsize - 1, ysize - 28);
-    boxfill(buf, xsize, White, 0, ysize - 27, xsize - 1, ysize - 27);
-    boxfill(buf, xsize, LightGray, 0, ysize - 26, xsize - 1, ysize - 1);
+    boxfill(buf, xsize, Color::DarkCyan, 0, 0, xsize - 1, ysize - 29);
+    boxfill(buf, xsize, Color::LightGray, 0, ysize - 28, xsize - 1, ysize - 28);
+    boxfill(buf, xsize, Color::White, 0, ysize - 27, xsize - 1, ysize - 27);
+    boxfill(buf, xsize, Color::LightGray, 0, ysize - 26, xsize - 1, ysize - 1);
 
-    boxfill(buf, xsize, White, 3, ysize - 24, 59, ysize - 24);
-    boxfill(buf, xsize, White, 2, ysize - 24, 2, ysize - 4);
-    boxfill(buf, xsize, DarkGray, 3, ysize - 4, 59, ysize - 4);
-    boxfill(buf, xsize, DarkGray, 59, ysize - 23, 59, ysize - 5);
-    boxfill(buf, xsize, Black, 2, ysize - 3, 59, ysize - 3);
-    boxfill(buf, xsize, Black, 60, ysize - 24, 60, ysize - 3);
+    boxfill(buf, xsize, Color::White, 3, ysize - 24, 59, ysize - 24);
+    boxfill(buf, xsize, Color::White, 2, ysize - 24, 2, ysize - 4);
+    boxfill(buf, xsize, Color::DarkGray, 3, ysize - 4, 59, ysize - 4);
+    boxfill(buf, xsize, Color::DarkGray, 59, ysize - 23, 59, ysize - 5);
+    boxfill(buf, xsize, Color::Black, 2, ysize - 3, 59, ysize - 3);
+    boxfill(buf, xsize, Color::Black, 60, ysize - 24, 60, ysize - 3);
 
     boxfill(
         buf,
         xsize,
-        DarkGray,
+        Color::DarkGray,
         xsize - 47,
         ysize - 24,
         xsize - 4,
@@ -96,7 +280,7 @@ pub fn init_screen(buf: usize) {
     boxfill(
         buf,
         xsize,
-        DarkGray,
+        Color::DarkGray,
         xsize - 47,
         ysize - 23,
         xsize - 47,
@@ -105,7 +289,7 @@ pub fn init_screen(buf: usize) {
     boxfill(
         buf,
         xsize,
-        White,
+        Color::White,
         xsize - 47,
         ysize - 3,
         xsize - 4,
@@ -114,7 +298,7 @@ pub fn init_screen(buf: usize) {
     boxfill(
         buf,
         xsize,
-        White,
+        Color::White,
         xsize - 3,
         ysize - 24,
         xsize - 3,
@@ -126,21 +310,68 @@ pub fn boxfill(buf: usize, xsize: isize, color: Color, x0: isize, y0: isize, x1:
     for y in y0..=y1 {
         for x in x0..=x1 {
             let ptr = unsafe { &mut *((buf as isize + y * xsize + x) as *mut u8) };
-            *ptr = color as u8;
+            *ptr = color.0;
         }
     }
 }
 
+pub fn draw_point(buf: usize, xsize: isize, color: Color, x: isize, y: isize) {
+    let ptr = unsafe { &mut *((buf as isize + y * xsize + x) as *mut u8) };
+    *ptr = color.0;
+}
+
+// 固定小数点(下位10ビット)でx, yを進めながら線を引く
+pub fn draw_line(buf: usize, xsize: isize, color: Color, x0: isize, y0: isize, x1: isize, y1: isize) {
+    let mut dx = (x1 - x0).abs();
+    let mut dy = (y1 - y0).abs();
+    let mut x = x0 << 10;
+    let mut y = y0 << 10;
+    let len: isize;
+    if dx >= dy {
+        len = dx + 1;
+        dx = if x0 > x1 { -1024 } else { 1024 };
+        dy = if y0 <= y1 {
+            ((y1 - y0 + 1) << 10) / len
+        } else {
+            ((y1 - y0 - 1) << 10) / len
+        };
+    } else {
+        len = dy + 1;
+        dy = if y0 > y1 { -1024 } else { 1024 };
+        dx = if x0 <= x1 {
+            ((x1 - x0 + 1) << 10) / len
+        } else {
+            ((x1 - x0 - 1) << 10) / len
+        };
+    }
+    for _ in 0..len {
+        draw_point(buf, xsize, color, x >> 10, y >> 10);
+        x += dx;
+        y += dy;
+    }
+}
+
 pub fn print_char(buf: usize, xsize: usize, char: u8, color: Color, startx: isize, starty: isize) {
+    draw_char(buf, xsize, PixelFormat::Indexed8, char, color, startx, starty);
+}
+
+// formatの形式のバッファに文字を描く(シート以外，VRAMに直接描くときに使う)
+pub fn draw_char(
+    buf: usize,
+    xsize: usize,
+    format: PixelFormat,
+    char: u8,
+    color: Color,
+    startx: isize,
+    starty: isize,
+) {
     let font = FONTS[char as usize];
-    let color = color as u8;
     let offset = startx + starty * xsize as isize;
     for y in 0..FONT_HEIGHT {
         for x in 0..FONT_WIDTH {
             if font[y][x] {
                 let cell = (y * xsize + x) as isize;
-                let ptr = unsafe { &mut *((buf as isize + cell + offset) as *mut u8) };
-                *ptr = color;
+                format.write(buf, (cell + offset) as usize, color);
             }
         }
     }
@@ -160,7 +391,7 @@ pub fn putblock(
     for y in 0..iysize {
         for x in 0..ixsize {
             let ptr = unsafe { &mut *((buf as isize + (py0 + y) * bxsize + (px0 + x)) as *mut u8) };
-            *ptr = image[y as usize][x as usize] as u8;
+            *ptr = image[y as usize][x as usize].0;
         }
     }
 }
@@ -211,15 +442,16 @@ impl fmt::Write for ScreenWriter {
                 self.newline();
                 continue;
             }
-            let buf_addr = if let Some(b) = self.buf_addr {
-                b
+            let (buf_addr, format) = if let Some(b) = self.buf_addr {
+                (b, PixelFormat::Indexed8)
             } else {
-                *VRAM_ADDR
+                (*VRAM_ADDR, *SCREEN_FORMAT)
             };
             if self.x + FONT_WIDTH <= width && self.y + FONT_HEIGHT <= height {
-                print_char(
+                draw_char(
                     buf_addr,
                     self.xsize,
+                    format,
                     str_bytes[i],
                     self.color,
                     self.x as isize,
@@ -228,9 +460,10 @@ impl fmt::Write for ScreenWriter {
             } else if self.y + FONT_HEIGHT * 2 < height {
                 // 1行ずらせば入る場合は1行ずらしてから表示
                 self.newline();
-                print_char(
+                draw_char(
                     buf_addr,
                     self.xsize,
+                    format,
                     str_bytes[i],
                     self.color,
                     self.x as isize,
@@ -269,7 +502,15 @@ macro_rules! write_with_bg {
     }}
 }
 
-pub fn make_window(buf: usize, xsize: isize, ysize: isize, title: &str, active: bool) {
+// resizableなら最大化ボタンも付ける
+pub fn make_window(
+    buf: usize,
+    xsize: isize,
+    ysize: isize,
+    title: &str,
+    active: bool,
+    resizable: bool,
+) {
     boxfill(buf, xsize, Color::LightGray, 0, 0, xsize - 1, 0);
     boxfill(buf, xsize, Color::White, 1, 1, xsize - 2, 1);
     boxfill(buf, xsize, Color::LightGray, 0, 0, 0, ysize - 1);
@@ -295,10 +536,17 @@ pub fn make_window(buf: usize, xsize: isize, ysize: isize, title: &str, active:
         ysize - 2,
     );
     boxfill(buf, xsize, Color::Black, 0, ysize - 1, xsize - 1, ysize - 1);
-    make_wtitle(buf, xsize, ysize, title, active);
+    make_wtitle(buf, xsize, ysize, title, active, resizable);
 }
 
-pub fn make_wtitle(buf: usize, xsize: isize, ysize: isize, title: &str, active: bool) {
+pub fn make_wtitle(
+    buf: usize,
+    xsize: isize,
+    ysize: isize,
+    title: &str,
+    active: bool,
+    resizable: bool,
+) {
     let closebtn: [&[u8; 16]; 14] = [
         b"OOOOOOOOOOOOOOO@",
         b"OQQQQQQQQQQQQQ$@",
@@ -334,11 +582,92 @@ pub fn make_wtitle(buf: usize, xsize: isize, ysize: isize, title: &str, active:
         ysize as usize,
     );
     write!(writer, "{}", title).unwrap();
+    let maxbtn: [&[u8; 16]; 14] = [
+        b"OOOOOOOOOOOOOOO@",
+        b"OQQQQQQQQQQQQQ$@",
+        b"OQQQQQQQQQQQQQ$@",
+        b"OQQ@@@@@@@@@QQ$@",
+        b"OQQ@@@@@@@@@QQ$@",
+        b"OQQ@QQQQQQQ@QQ$@",
+        b"OQQ@QQQQQQQ@QQ$@",
+        b"OQQ@QQQQQQQ@QQ$@",
+        b"OQQ@QQQQQQQ@QQ$@",
+        b"OQQ@QQQQQQQ@QQ$@",
+        b"OQQ@@@@@@@@@QQ$@",
+        b"OQQQQQQQQQQQQQ$@",
+        b"O$$$$$$$$$$$$$$@",
+        b"@@@@@@@@@@@@@@@@",
+    ];
+    let minbtn: [&[u8; 16]; 14] = [
+        b"OOOOOOOOOOOOOOO@",
+        b"OQQQQQQQQQQQQQ$@",
+        b"OQQQQQQQQQQQQQ$@",
+        b"OQQQQQQQQQQQQQ$@",
+        b"OQQQQQQQQQQQQQ$@",
+        b"OQQQQQQQQQQQQQ$@",
+        b"OQQQQQQQQQQQQQ$@",
+        b"OQQQQQQQQQQQQQ$@",
+        b"OQQQ@@@@@@@QQQ$@",
+        b"OQQQ@@@@@@@QQQ$@",
+        b"OQQQQQQQQQQQQQ$@",
+        b"OQQQQQQQQQQQQQ$@",
+        b"O$$$$$$$$$$$$$$@",
+        b"@@@@@@@@@@@@@@@@",
+    ];
+    for &(button, pattern) in [
+        (WButton::Close, &closebtn),
+        (WButton::Maximize, &maxbtn),
+        (WButton::Minimize, &minbtn),
+    ]
+    .iter()
+    {
+        if let Some(x0) = wbutton_x(button, xsize, resizable) {
+            put_wbutton(buf, xsize, x0, pattern);
+        }
+    }
+}
+
+// タイトルバーのボタン．どれもy = 5からの16x14
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WButton {
+    Close,
+    Maximize,
+    Minimize,
+}
+
+// ボタンの左端のx．付けないボタンならNone
+// 最大化ボタンは大きさを変えられるウィンドウにだけ付け，最小化ボタンはその左に並べる
+// タイトルバー(x = 3から)に収まらないボタンも付けない
+pub fn wbutton_x(button: WButton, xsize: isize, resizable: bool) -> Option<isize> {
+    let right = match button {
+        WButton::Close => 21,
+        WButton::Maximize if resizable => 37,
+        WButton::Maximize => return None,
+        WButton::Minimize if resizable => 53,
+        WButton::Minimize => 37,
+    };
+    if xsize - right >= 3 {
+        Some(xsize - right)
+    } else {
+        None
+    }
+}
+
+// タイトルバーのうちボタンより左の部分の右端
+fn wtitle_end(xsize: isize, resizable: bool) -> isize {
+    [WButton::Close, WButton::Maximize, WButton::Minimize]
+        .iter()
+        .filter_map(|&button| wbutton_x(button, xsize, resizable))
+        .min()
+        .unwrap_or(xsize - 3)
+}
+
+fn put_wbutton(buf: usize, xsize: isize, x0: isize, pattern: &[&[u8; 16]; 14]) {
     for y in 0..14 {
         let y = y as usize;
         for x in 0..16 {
             let x = x as usize;
-            let c = closebtn[y][x];
+            let c = pattern[y][x];
             let color: Color;
             if c == b'@' {
                 color = Color::Black
@@ -350,13 +679,31 @@ pub fn make_wtitle(buf: usize, xsize: isize, ysize: isize, title: &str, active:
                 color = Color::White
             }
             let ptr = unsafe {
-                &mut *((buf + (5 + y) * xsize as usize + (xsize as usize - 21 + x)) as *mut Color)
+                &mut *((buf + (5 + y) * xsize as usize + x0 as usize + x) as *mut Color)
             };
             *ptr = color;
         }
     }
 }
 
+// タイトルバーの色だけを塗り替えて，アクティブかどうかを切り替える
+// タイトル文字列とボタンはそのまま
+pub fn change_wtitle(buf: usize, xsize: isize, active: bool, resizable: bool) {
+    let (old_bg, new_bg) = if active {
+        (Color::DarkGray, Color::DarkBlue)
+    } else {
+        (Color::DarkBlue, Color::DarkGray)
+    };
+    for y in 3..=20 {
+        for x in 3..wtitle_end(xsize, resizable) {
+            let ptr = unsafe { &mut *((buf as isize + y * xsize + x) as *mut Color) };
+            if *ptr == old_bg {
+                *ptr = new_bg;
+            }
+        }
+    }
+}
+
 pub fn make_textbox(
     buf: usize,
     bxsize: isize,